@@ -0,0 +1,104 @@
+// Everything one node knows about itself: how it was set up, and the state its tasks share.
+// Nothing of it is process-wide, so any number of nodes can run side by side in one process.
+use crate::dir::index;
+use crate::download::{self, SourcePolicy};
+use crate::networking::{
+    self, Downloaders, DEFAULT_PEER_EXPIRY_SECS, DEFAULT_WINDOW_SIZE, UDP_GET_PORT,
+};
+use crate::search;
+use crate::udp::headers::ConnectionType;
+use crate::udp::link::LinkConditions;
+use crate::udp::query;
+use crate::udp::rtt::PeerRtts;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+#[derive(Clone)]
+pub struct NodeConfig {
+    // The directory whose files this node shares, and where its downloads go.
+    pub static_dir: String,
+    pub ip: Ipv4Addr,
+    // The first port tried for the control socket, the next ones are tried if it's taken.
+    // Zero leaves the choice of any of the ports to the OS.
+    pub control_port: u16,
+    pub data_sender_port: u16,
+    pub data_receiver_port: u16,
+    pub conn_type: ConnectionType,
+    // The sender's window size for gbn and sr.
+    pub window_size: u32,
+    // How long a silent peer is kept around.
+    pub peer_expiry: Duration,
+    // Download from a single peer picked by this policy, instead of all of them.
+    pub source_policy: Option<SourcePolicy>,
+    // What the emulated link does to the reliable UDP datagrams we send.
+    pub link: LinkConditions,
+}
+
+impl Default for NodeConfig {
+    fn default() -> NodeConfig {
+        NodeConfig {
+            static_dir: String::from("./static/"),
+            ip: Ipv4Addr::new(127, 0, 0, 1),
+            control_port: UDP_GET_PORT,
+            data_sender_port: networking::random_data_port(),
+            data_receiver_port: networking::random_data_port(),
+            conn_type: ConnectionType::default(),
+            window_size: DEFAULT_WINDOW_SIZE,
+            peer_expiry: Duration::from_secs(DEFAULT_PEER_EXPIRY_SECS),
+            source_policy: None,
+            link: LinkConditions::default(),
+        }
+    }
+}
+
+pub struct NodeContext {
+    pub config: NodeConfig,
+    pub id: u128,
+    // The ports the control and data sockets actually got, which peers know us by.
+    control_port: AtomicU16,
+    data_port: AtomicU16,
+    pub data_clients: RwLock<u16>,
+    pub downloaders: Downloaders,
+    pub index: index::Index,
+    pub routes: query::Routes,
+    pub throughput: download::Throughput,
+    pub rtts: PeerRtts,
+    pub last_results: search::LastResults,
+}
+
+impl NodeContext {
+    pub fn new(config: NodeConfig, id: u128) -> NodeContext {
+        NodeContext {
+            control_port: AtomicU16::new(config.control_port),
+            data_port: AtomicU16::new(config.data_sender_port),
+            config,
+            id,
+            data_clients: RwLock::new(0),
+            downloaders: Default::default(),
+            index: Default::default(),
+            routes: Default::default(),
+            throughput: Default::default(),
+            rtts: Default::default(),
+            last_results: Default::default(),
+        }
+    }
+
+    pub fn control_port(&self) -> u16 {
+        self.control_port.load(Ordering::Relaxed)
+    }
+
+    pub fn set_control_port(&self, port: u16) {
+        self.control_port.store(port, Ordering::Relaxed);
+    }
+
+    // Zero until the data server is up, when the OS picks its port.
+    pub fn data_port(&self) -> u16 {
+        self.data_port.load(Ordering::Relaxed)
+    }
+
+    pub fn set_data_port(&self, port: u16) {
+        self.data_port.store(port, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,299 @@
+// The index of shared files: size, modification time and hashes of each one, so nothing has to
+// list the shared directory or hash a file on every request.
+//
+// It's kept in a sidecar file in the shared directory, one line per file after a version line:
+//
+// size \t modified (ns since the epoch) \t digest (hex) \t piece hashes (hex, back to back) \t name
+//
+// While the shared directory is being watched, the index follows its changes on its own.
+// Otherwise an entry is only trusted while the file's size and modification time still match it,
+// and the file is hashed again when they don't. Names can't contain newlines, so the name goes last.
+use super::merkle::{hash_file, FileHashes};
+use super::{file_list, hex_digest, normalize_shared_path, parse_hex_digest, resolve_shared};
+use crate::context::NodeContext;
+use notify::{RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+
+const INDEX_FILE_NAME: &str = ".netwolf-index";
+const INDEX_VERSION_LINE: &str = "netwolf-index 2";
+// A file is only hashed again once it has stopped changing for this long.
+const WATCH_DEBOUNCE_MS: u64 = 500;
+
+#[derive(Clone)]
+pub struct IndexEntry {
+    pub size: u64,
+    pub modified: u64,
+    pub hashes: FileHashes,
+}
+
+// A node's index, along with whether it's being kept up to date by a watcher.
+#[derive(Default)]
+pub struct Index {
+    entries: RwLock<HashMap<String, IndexEntry>>,
+    save_lock: Mutex<()>,
+    watching: AtomicBool,
+}
+
+// Loads the sidecar, brings it up to date with the shared directory and saves it back.
+pub fn open(ctx: &NodeContext) -> std::io::Result<()> {
+    let mut cached = load(ctx);
+    let mut fresh = HashMap::new();
+    for name in file_list(&ctx.config) {
+        let entry = match cached.remove(&name) {
+            Some(entry) if is_current(ctx, &name, &entry) => entry,
+            _ => match index_file(ctx, &name) {
+                Some(entry) => entry,
+                None => continue,
+            },
+        };
+        fresh.insert(name, entry);
+    }
+    info!("Indexed {} shared files", fresh.len());
+    *ctx.index.entries.write().unwrap() = fresh;
+    save(ctx)
+}
+
+// The entry of a shared file. Without a watcher, it's re-indexed first if it has changed on disk since.
+pub fn lookup(ctx: &NodeContext, file_name: &str) -> Option<IndexEntry> {
+    let file_name = &normalize_shared_path(file_name)?;
+    let cached = ctx.index.entries.read().unwrap().get(file_name).cloned();
+    if ctx.index.watching.load(Ordering::Relaxed) {
+        return cached;
+    }
+    if let Some(entry) = cached {
+        if is_current(ctx, file_name, &entry) {
+            return Some(entry);
+        }
+    }
+    refresh(ctx, file_name)
+}
+
+// The same lookup for the async side of the node, kept off its runtime
+// since without a watcher it may have to hash the file first.
+pub async fn lookup_async(ctx: &Arc<NodeContext>, file_name: &str) -> Option<IndexEntry> {
+    let ctx = ctx.clone();
+    let file_name = file_name.to_string();
+    tokio::task::spawn_blocking(move || lookup(&ctx, &file_name))
+        .await
+        .ok()
+        .flatten()
+}
+
+// The names of every shared file the index knows of.
+pub fn file_names(ctx: &NodeContext) -> Vec<String> {
+    ctx.index.entries.read().unwrap().keys().cloned().collect()
+}
+
+// Keeps the index in step with the shared directory until the node shuts down.
+// Deleted files are forgotten right away, new and modified ones once they've settled.
+// It blocks on the watcher's events, so it belongs on a thread of its own.
+pub fn watch(ctx: Arc<NodeContext>, shutdown: CancellationToken) {
+    let shared_dir = match fs::canonicalize(&ctx.config.static_dir) {
+        Ok(dir) => dir,
+        Err(e) => {
+            warn!("Can't find the shared directory: {}", e);
+            return;
+        }
+    };
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            warn!(
+                "Can't watch the shared directory, checking files on every request: {}",
+                e
+            );
+            return;
+        }
+    };
+    if let Err(e) = watcher.watch(&shared_dir, RecursiveMode::Recursive) {
+        warn!(
+            "Can't watch the shared directory, checking files on every request: {}",
+            e
+        );
+        return;
+    }
+    ctx.index.watching.store(true, Ordering::Relaxed);
+    // Whatever changed before the watch started has to be caught up on.
+    if let Err(e) = open(&ctx) {
+        warn!("Couldn't save the file index: {}", e);
+    }
+    let debounce = Duration::from_millis(WATCH_DEBOUNCE_MS);
+    let mut settling: HashSet<String> = HashSet::new();
+    let mut rescan = false;
+    // Waking up at least once per debounce is what notices the shutdown.
+    while !shutdown.is_cancelled() {
+        match rx.recv_timeout(debounce) {
+            Ok(Ok(event)) => {
+                for path in event.paths {
+                    let relative = match path.strip_prefix(&shared_dir) {
+                        Ok(relative) => relative.to_string_lossy().replace('\\', "/"),
+                        Err(_) => continue,
+                    };
+                    let file_name = match normalize_shared_path(&relative) {
+                        Some(file_name) => file_name,
+                        None => continue,
+                    };
+                    let is_indexed = ctx.index.entries.read().unwrap().contains_key(&file_name);
+                    match stat(&ctx, &file_name) {
+                        Some(_) => {
+                            settling.insert(file_name);
+                        }
+                        None if is_indexed => {
+                            settling.remove(&file_name);
+                            refresh(&ctx, &file_name);
+                        }
+                        // A whole directory came or went, everything under it needs a look.
+                        None => rescan = true,
+                    }
+                }
+            }
+            Ok(Err(e)) => warn!("Error while watching the shared directory: {}", e),
+            Err(RecvTimeoutError::Timeout) if rescan => {
+                rescan = false;
+                settling.clear();
+                if let Err(e) = open(&ctx) {
+                    warn!("Couldn't save the file index: {}", e);
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                for file_name in settling.drain() {
+                    refresh(&ctx, &file_name);
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    ctx.index.watching.store(false, Ordering::Relaxed);
+}
+
+// Re-indexes a single file, or forgets it if it's gone.
+fn refresh(ctx: &NodeContext, file_name: &str) -> Option<IndexEntry> {
+    // Hashing happens without holding the lock, the other requests shouldn't wait for it.
+    let entry = index_file(ctx, file_name);
+    let mut index_ptr = ctx.index.entries.write().unwrap();
+    let old_entry = match &entry {
+        Some(entry) => index_ptr.insert(file_name.to_string(), entry.clone()),
+        None => index_ptr.remove(file_name),
+    };
+    drop(index_ptr);
+    match (&entry, &old_entry) {
+        (Some(_), None) => info!("Now sharing {}", file_name),
+        (None, Some(_)) => info!("No longer sharing {}", file_name),
+        _ => {}
+    }
+    if entry.is_some() || old_entry.is_some() {
+        if let Err(e) = save(ctx) {
+            warn!("Couldn't save the file index: {}", e);
+        }
+    }
+    entry
+}
+
+fn stat(ctx: &NodeContext, file_name: &str) -> Option<(u64, u64)> {
+    let meta = fs::metadata(resolve_shared(&ctx.config, file_name)?).ok()?;
+    if !meta.is_file() {
+        return None;
+    }
+    let modified = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((meta.len(), modified.as_nanos() as u64))
+}
+
+fn is_current(ctx: &NodeContext, file_name: &str, entry: &IndexEntry) -> bool {
+    stat(ctx, file_name) == Some((entry.size, entry.modified))
+}
+
+fn index_file(ctx: &NodeContext, file_name: &str) -> Option<IndexEntry> {
+    let (size, modified) = stat(ctx, file_name)?;
+    info!("Hashing {}", file_name);
+    let hashes = hash_file(&resolve_shared(&ctx.config, file_name)?).ok()?;
+    Some(IndexEntry {
+        size,
+        modified,
+        hashes,
+    })
+}
+
+fn index_address(ctx: &NodeContext) -> PathBuf {
+    PathBuf::from(&ctx.config.static_dir).join(INDEX_FILE_NAME)
+}
+
+// A missing or unreadable sidecar just means everything gets hashed again.
+fn load(ctx: &NodeContext) -> HashMap<String, IndexEntry> {
+    let mut index = HashMap::new();
+    let contents = match fs::read_to_string(index_address(ctx)) {
+        Ok(contents) => contents,
+        Err(_) => return index,
+    };
+    let mut lines = contents.lines();
+    if lines.next() != Some(INDEX_VERSION_LINE) {
+        warn!("Ignoring a file index of another version");
+        return index;
+    }
+    for line in lines {
+        match parse_entry(line) {
+            Some((name, entry)) => {
+                index.insert(name, entry);
+            }
+            None => warn!("Ignoring a damaged line of the file index"),
+        }
+    }
+    index
+}
+
+fn parse_entry(line: &str) -> Option<(String, IndexEntry)> {
+    let mut fields = line.splitn(5, '\t');
+    let size = fields.next()?.parse().ok()?;
+    let modified = fields.next()?.parse().ok()?;
+    let digest = parse_hex_digest(fields.next()?)?;
+    let piece_field = fields.next()?;
+    let name = fields.next()?.to_string();
+    if piece_field.len() % 64 != 0 {
+        return None;
+    }
+    let mut pieces = Vec::new();
+    for start in (0..piece_field.len()).step_by(64) {
+        pieces.push(parse_hex_digest(piece_field.get(start..start + 64)?)?);
+    }
+    let entry = IndexEntry {
+        size,
+        modified,
+        hashes: FileHashes {
+            size,
+            digest,
+            pieces,
+        },
+    };
+    Some((name, entry))
+}
+
+// Written next to the old one and renamed over it, so a crash never leaves half an index.
+fn save(ctx: &NodeContext) -> std::io::Result<()> {
+    let _saving = ctx.index.save_lock.lock().unwrap();
+    let mut contents = format!("{}\n", INDEX_VERSION_LINE);
+    for (name, entry) in ctx.index.entries.read().unwrap().iter() {
+        let pieces: String = entry.hashes.pieces.iter().map(hex_digest).collect();
+        contents.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            entry.size,
+            entry.modified,
+            hex_digest(&entry.hashes.digest),
+            pieces,
+            name
+        ));
+    }
+    let index_addr = index_address(ctx);
+    let temp_addr = index_addr.with_extension("tmp");
+    let mut temp = fs::File::create(&temp_addr)?;
+    temp.write_all(contents.as_bytes())?;
+    temp.sync_all()?;
+    fs::rename(temp_addr, index_addr)
+}
@@ -0,0 +1,84 @@
+// Shared files are split into PIECE_SIZE pieces, each with its own SHA-256 hash, and a Merkle
+// tree over those hashes lets the single root sent in a GET ACK vouch for every one of them.
+//
+// Every leaf is SHA-256(0x00 | piece) and every parent is SHA-256(0x01 | left | right), so no
+// piece can pass for a parent. A node without a sibling is carried up to the next level as is.
+use super::FileDigest;
+use crate::networking::PIECE_SIZE;
+use sha2::{Digest, Sha256};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+const LEAF_PREFIX: u8 = 0;
+const PARENT_PREFIX: u8 = 1;
+
+#[derive(Clone)]
+pub struct FileHashes {
+    pub size: u64,
+    pub digest: FileDigest,
+    pub pieces: Vec<FileDigest>,
+}
+
+impl FileHashes {
+    pub fn merkle_root(&self) -> FileDigest {
+        merkle_root(&self.pieces)
+    }
+}
+
+// Reads the file once for both its whole-file digest and its piece hashes.
+pub fn hash_file(file_addr: &Path) -> std::io::Result<FileHashes> {
+    hash_contents(BufReader::new(File::open(file_addr)?))
+}
+
+pub fn hash_contents<R: Read>(mut input: R) -> std::io::Result<FileHashes> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut pieces = Vec::new();
+    let mut piece = Vec::with_capacity(PIECE_SIZE as usize);
+    loop {
+        piece.clear();
+        (&mut input).take(PIECE_SIZE).read_to_end(&mut piece)?;
+        if piece.is_empty() {
+            break;
+        }
+        hasher.update(&piece);
+        size += piece.len() as u64;
+        pieces.push(piece_hash(&piece));
+    }
+    Ok(FileHashes {
+        size,
+        digest: hasher.finalize().into(),
+        pieces,
+    })
+}
+
+pub fn piece_hash(piece: &[u8]) -> FileDigest {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(piece);
+    hasher.finalize().into()
+}
+
+pub fn merkle_root(pieces: &[FileDigest]) -> FileDigest {
+    if pieces.is_empty() {
+        return piece_hash(&[]);
+    }
+    let mut level = pieces.to_vec();
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update([PARENT_PREFIX]);
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().into()
+                }
+                _ => pair[0],
+            })
+            .collect();
+    }
+    level[0]
+}
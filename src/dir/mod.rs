@@ -1,23 +1,142 @@
-use crate::STATIC_DIR;
+use crate::context::NodeConfig;
+use crate::networking::BUF_SIZE;
+use sha2::{Digest, Sha256};
 use std::ffi::OsStr;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{BufReader, Error, ErrorKind, Read, SeekFrom};
 use std::path::{Path, PathBuf};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, Take};
+pub mod index;
+pub mod merkle;
 
-pub fn file_list() -> Vec<String> {
-    let dir_lock = STATIC_DIR.read().unwrap();
-    let dir = &*dir_lock;
-    let paths = fs::read_dir(dir).unwrap();
+// The part of a shared file a single data GET asked for.
+pub type FileRange = Take<tokio::io::BufReader<tokio::fs::File>>;
+// A SHA-256 digest of a file's whole content.
+pub type FileDigest = [u8; 32];
+
+// Every shared file, as a path relative to the shared directory like `music/a.mp3`.
+pub fn file_list(config: &NodeConfig) -> Vec<String> {
+    let root = PathBuf::from(&config.static_dir);
     let mut result = vec![];
-    for path in paths {
-        let path_str = path.unwrap().file_name().to_str().unwrap().to_string();
-        result.push(path_str);
-    }
+    list_into(config, &root, "", &mut result);
     result
 }
 
+// Dotfiles are the node's own bookkeeping, not something to share. Symlinked directories
+// aren't descended into, so a link loop can't trap the walk.
+fn list_into(config: &NodeConfig, dir: &Path, prefix: &str, result: &mut Vec<String>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let name = match entry.file_name().to_str() {
+            Some(name) if !name.starts_with('.') => name.to_string(),
+            _ => continue,
+        };
+        let relative = format!("{}{}", prefix, name);
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {
+                list_into(config, &entry.path(), &format!("{}/", relative), result)
+            }
+            Ok(_) if resolve_shared(config, &relative).is_some() => result.push(relative),
+            _ => {}
+        }
+    }
+}
+
+// Turns a path a peer or the user gave into the relative, '/'-separated form files are
+// shared under. Absolute paths, `..` and hidden components are refused rather than fixed up.
+pub fn normalize_shared_path(file_name: &str) -> Option<String> {
+    if file_name.starts_with('/') || file_name.contains(['\\', ':', '\n', '\0']) {
+        return None;
+    }
+    let mut components = Vec::new();
+    for component in file_name.split('/') {
+        match component {
+            "" | "." => continue,
+            _ if component.starts_with('.') => return None,
+            _ => components.push(component),
+        }
+    }
+    if components.is_empty() {
+        return None;
+    }
+    Some(components.join("/"))
+}
+
+// Where a shared file really is, as long as that's inside the shared directory:
+// symlinks are only followed while they point back into it.
+pub fn resolve_shared(config: &NodeConfig, file_name: &str) -> Option<PathBuf> {
+    let relative = normalize_shared_path(file_name)?;
+    let root = fs::canonicalize(&config.static_dir).ok()?;
+    let path = fs::canonicalize(root.join(relative)).ok()?;
+    if path.starts_with(&root) {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+pub fn digest_of(file_addr: &str) -> std::io::Result<FileDigest> {
+    let mut input = BufReader::new(File::open(file_addr)?);
+    let mut hasher = Sha256::new();
+    let mut buf = [0; BUF_SIZE];
+    loop {
+        let size = input.read(&mut buf)?;
+        if size == 0 {
+            return Ok(hasher.finalize().into());
+        }
+        hasher.update(&buf[..size]);
+    }
+}
+
+pub fn hex_digest(digest: &FileDigest) -> String {
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn parse_hex_digest(hex: &str) -> Option<FileDigest> {
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut digest = [0; 32];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+// Without a length, the range runs to the end of the file.
+pub async fn open_range(
+    config: &NodeConfig,
+    file_name: &str,
+    offset: u64,
+    length: Option<u64>,
+) -> std::io::Result<FileRange> {
+    let file_addr = resolve_shared(config, file_name)
+        .ok_or_else(|| Error::new(ErrorKind::NotFound, "not a shared file"))?;
+    let mut f = tokio::fs::File::open(file_addr).await?;
+    f.seek(SeekFrom::Start(offset)).await?;
+    Ok(tokio::io::BufReader::new(f).take(length.unwrap_or(u64::MAX)))
+}
+
+// Where an unfinished download and the record of its finished pieces are kept.
+// They're dotfiles, so half a file is never shared with anyone.
+pub fn partial_file_addresses(config: &NodeConfig, file_name: &str) -> (String, String) {
+    let final_addr = PathBuf::from(generate_file_address(config, file_name, true));
+    let final_name = final_addr.file_name().unwrap().to_string_lossy();
+    let part_addr = final_addr.with_file_name(format!(".{}.part", final_name));
+    let progress_addr = final_addr.with_file_name(format!(".{}.pieces", final_name));
+    (
+        part_addr.to_string_lossy().to_string(),
+        progress_addr.to_string_lossy().to_string(),
+    )
+}
+
 // To avoid over-writing already existing files.
-pub fn generate_file_address(file_name: &str, sr: bool) -> String {
-    let static_dir = &*STATIC_DIR.read().unwrap();
+// Nested names like `music/a.mp3` are kept in their own subdirectory.
+pub fn generate_file_address(config: &NodeConfig, file_name: &str, sr: bool) -> String {
+    let static_dir = &config.static_dir;
     let buf_immut = PathBuf::new().join(static_dir).join(file_name);
     let mut display_str = String::from(buf_immut.to_str().unwrap());
     // This stupid duplication is the only way I could get away with
@@ -30,7 +149,7 @@ pub fn generate_file_address(file_name: &str, sr: bool) -> String {
         let mut lossy_string = file_name.to_string_lossy();
         let b = lossy_string.to_mut();
         b.push_str("-1");
-        let file_path_buf = PathBuf::new().join(static_dir);
+        let file_path_buf = buf_immut.parent().unwrap().to_path_buf();
         let mut file_path_buf = file_path_buf.join(b);
         file_path_buf.set_extension(file_extension);
         display_str = String::from(Path::new(&file_path_buf).to_str().unwrap());
@@ -0,0 +1,573 @@
+use crate::context::NodeContext;
+use crate::dir::merkle::{merkle_root, piece_hash};
+use crate::dir::{
+    digest_of, generate_file_address, hex_digest, partial_file_addresses, FileDigest,
+};
+use crate::error::NetWolfError;
+use crate::networking::{
+    spawn_until, GET_ACK_WINDOW_MS, MAX_BAD_PIECES, MAX_FILE_SIZE, PIECE_SIZE,
+};
+use crate::udp::fetch_piece_hashes;
+use log::{info, warn};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::{self, JoinSet};
+use tokio::time::{sleep, timeout};
+use tokio_util::sync::CancellationToken;
+
+pub type FetchFuture = Pin<Box<dyn Future<Output = std::io::Result<Vec<u8>>> + Send>>;
+// Fetches `length` bytes of a file, starting at `offset`, from a peer's data socket.
+pub type RangeFetcher = fn(Arc<NodeContext>, SocketAddr, String, u64, u64) -> FetchFuture;
+// Bytes per second each peer has sent us pieces at, by its control address.
+pub type Throughput = Mutex<HashMap<SocketAddr, f64>>;
+
+pub enum DownloadEvent {
+    // The user asked for a file, so GET ACKs for it are welcome for a while.
+    // Picked off a search, it has to be the very content that was listed.
+    Requested {
+        file_name: String,
+        digest: Option<FileDigest>,
+    },
+    // A peer ACK'd our GET and is ready to serve the file on its data socket.
+    Offered {
+        file_name: String,
+        control_addr: SocketAddr,
+        data_addr: SocketAddr,
+        file_size: u64,
+        digest: FileDigest,
+        merkle_root: FileDigest,
+        prior_comms: u16,
+    },
+}
+
+// How to pick the one peer a file is downloaded from, instead of the whole swarm.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SourcePolicy {
+    // The peer whose GET ACK came back first.
+    LowestRtt,
+    // The peer that has started the fewest downloads from us, however many pieces each one took.
+    FewestComms,
+    // The peer that has sent us pieces the fastest so far, unknown peers last.
+    HighestThroughput,
+}
+
+impl FromStr for SourcePolicy {
+    type Err = NetWolfError;
+
+    fn from_str(name: &str) -> Result<SourcePolicy, NetWolfError> {
+        match name {
+            "rtt" => Ok(SourcePolicy::LowestRtt),
+            "comms" => Ok(SourcePolicy::FewestComms),
+            "throughput" => Ok(SourcePolicy::HighestThroughput),
+            _ => Err(NetWolfError::UnknownSourcePolicy(name.to_string())),
+        }
+    }
+}
+
+// What a peer says about the file it offers; peers agreeing on it are serving the same file.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Content {
+    size: u64,
+    digest: FileDigest,
+    merkle_root: FileDigest,
+}
+
+struct Offer {
+    control_addr: SocketAddr,
+    data_addr: SocketAddr,
+    content: Content,
+    // How long after our GET its ACK arrived.
+    rtt: Duration,
+    prior_comms: u16,
+}
+
+struct PendingDownload {
+    requested_at: Instant,
+    digest: Option<FileDigest>,
+    offers: Vec<Offer>,
+}
+
+// Pieces nobody has fetched yet, and how many are being fetched right now.
+struct PieceQueue {
+    missing: VecDeque<u64>,
+    in_flight: u64,
+}
+
+// Everything the peers of one swarm share.
+struct Swarm {
+    ctx: Arc<NodeContext>,
+    file_name: String,
+    file_size: u64,
+    fetcher: RangeFetcher,
+    piece_hashes: Vec<FileDigest>,
+    queue: Mutex<PieceQueue>,
+    // The partial file and the record of its finished pieces.
+    output: Mutex<(File, File)>,
+}
+
+impl Swarm {
+    // A piece only counts as finished once all of it is in the partial file.
+    fn write_piece(&self, piece: u64, data: &[u8]) -> std::io::Result<()> {
+        let mut output_ptr = self.output.lock().unwrap();
+        let (part, progress) = &mut *output_ptr;
+        part.seek(SeekFrom::Start(piece * PIECE_SIZE))?;
+        part.write_all(data)?;
+        writeln!(progress, "{}", piece)
+    }
+}
+
+// Collects the GET ACKs of every request for a while, then downloads the file from all of them at once,
+// or from the best of them by the node's source policy.
+// Downloads are cancelled along with the node, their partial files are resumed next time.
+pub async fn download_manager(
+    ctx: Arc<NodeContext>,
+    mut receiver: UnboundedReceiver<DownloadEvent>,
+    fetcher: RangeFetcher,
+    shutdown: CancellationToken,
+) {
+    let ack_window = Duration::from_millis(GET_ACK_WINDOW_MS);
+    let mut pending: HashMap<String, PendingDownload> = HashMap::new();
+    // Files being downloaded right now. A second swarm would write over the same partial file.
+    let in_flight: Arc<Mutex<HashSet<String>>> = Arc::default();
+    loop {
+        match timeout(ack_window / 10, receiver.recv()).await {
+            Ok(Some(DownloadEvent::Requested { file_name, digest })) => {
+                if pending.contains_key(&file_name)
+                    || in_flight.lock().unwrap().contains(&file_name)
+                {
+                    println!("Already downloading {}", file_name);
+                } else {
+                    pending.insert(
+                        file_name,
+                        PendingDownload {
+                            requested_at: Instant::now(),
+                            digest,
+                            offers: Vec::new(),
+                        },
+                    );
+                }
+            }
+            Ok(Some(DownloadEvent::Offered {
+                file_name,
+                control_addr,
+                data_addr,
+                file_size,
+                digest,
+                merkle_root,
+                prior_comms,
+            })) => match pending.get_mut(&file_name) {
+                Some(_) if file_size > MAX_FILE_SIZE => {
+                    warn!(
+                        "Ignoring an offer of {} from {} at {} bytes",
+                        file_name, data_addr, file_size
+                    )
+                }
+                Some(download) if download.digest.is_some_and(|wanted| wanted != digest) => {
+                    info!(
+                        "Ignoring an offer of {} from {} with other content",
+                        file_name, data_addr
+                    )
+                }
+                Some(download) => {
+                    if !download
+                        .offers
+                        .iter()
+                        .any(|offer| offer.data_addr == data_addr)
+                    {
+                        download.offers.push(Offer {
+                            control_addr,
+                            data_addr,
+                            content: Content {
+                                size: file_size,
+                                digest,
+                                merkle_root,
+                            },
+                            rtt: download.requested_at.elapsed(),
+                            prior_comms,
+                        });
+                    }
+                }
+                // Either we never asked, or the ACK came too late to be part of the swarm.
+                None => info!("Ignoring an offer of {} from {}", file_name, data_addr),
+            },
+            Err(_) => {}
+            Ok(None) => return,
+        }
+        let ready: Vec<String> = pending
+            .iter()
+            .filter(|(_, download)| download.requested_at.elapsed() >= ack_window)
+            .map(|(file_name, _)| file_name.clone())
+            .collect();
+        for file_name in ready {
+            let download = pending.remove(&file_name).unwrap();
+            if download.offers.is_empty() {
+                println!("No peer has {}", file_name);
+                continue;
+            }
+            let ctx = ctx.clone();
+            let in_flight = in_flight.clone();
+            in_flight.lock().unwrap().insert(file_name.clone());
+            spawn_until(&shutdown, async move {
+                match swarm_download(ctx, &file_name, download.offers, fetcher).await {
+                    Ok(()) => println!("Finished downloading {}", file_name),
+                    Err(e) => println!("Couldn't download {}: {}", file_name, e),
+                }
+                in_flight.lock().unwrap().remove(&file_name);
+            });
+        }
+    }
+}
+
+// Splits the file into pieces and has every peer that offered it fetch them in parallel,
+// each peer taking the next missing piece as soon as it's done with its last one.
+// Pieces are written to a partial file first, so an interrupted download picks up where it stopped,
+// and it only takes the file's name once its content matches the digest the peers promised.
+// With a policy, the peers take turns instead, best first, each one picking up where the last failed.
+async fn swarm_download(
+    ctx: Arc<NodeContext>,
+    file_name: &str,
+    offers: Vec<Offer>,
+    fetcher: RangeFetcher,
+) -> std::io::Result<()> {
+    let policy = ctx.config.source_policy;
+    // Peers disagreeing on the content can't be serving the same file, so the majority wins.
+    let mut votes: HashMap<Content, usize> = HashMap::new();
+    for offer in &offers {
+        *votes.entry(offer.content).or_insert(0) += 1;
+    }
+    let content = votes
+        .into_iter()
+        .max_by_key(|(_, votes)| *votes)
+        .map(|(content, _)| content)
+        .unwrap();
+    let mut offers: Vec<Offer> = offers
+        .into_iter()
+        .filter(|offer| offer.content == content)
+        .collect();
+    if let Some(policy) = policy {
+        rank_offers(&ctx, &mut offers, policy);
+    }
+    if content.size > MAX_FILE_SIZE {
+        return Err(Error::new(ErrorKind::InvalidData, "file is too big"));
+    }
+    let piece_count = content.size.div_ceil(PIECE_SIZE);
+    let piece_hashes = agreed_piece_hashes(&ctx, file_name, &offers, &content).await?;
+    let record_header = format!("{} {}", content.size, hex_digest(&content.digest));
+    info!(
+        "Downloading {} ({} pieces) from {} peers",
+        file_name,
+        piece_count,
+        offers.len()
+    );
+    let (part_addr, progress_addr) = partial_file_addresses(&ctx.config, file_name);
+    // Checking the pieces means reading all of the partial file, so it's kept off the runtime,
+    // and so is the rest of what's done to the files.
+    let (done, output, progress) = {
+        let (part_addr, progress_addr) = (part_addr.clone(), progress_addr.clone());
+        let piece_hashes = piece_hashes.clone();
+        task::spawn_blocking(move || {
+            open_partial_files(
+                &part_addr,
+                &progress_addr,
+                &record_header,
+                content.size,
+                &piece_hashes,
+            )
+        })
+        .await
+        .map_err(Error::other)??
+    };
+    if !done.is_empty() {
+        println!(
+            "Resuming {}, {} of {} pieces are already here",
+            file_name,
+            done.len(),
+            piece_count
+        );
+    }
+    let swarm = Arc::new(Swarm {
+        ctx: ctx.clone(),
+        file_name: file_name.to_string(),
+        file_size: content.size,
+        fetcher,
+        piece_hashes,
+        queue: Mutex::new(PieceQueue {
+            missing: (0..piece_count)
+                .filter(|piece| !done.contains(piece))
+                .collect(),
+            in_flight: 0,
+        }),
+        output: Mutex::new((output, progress)),
+    });
+    let turns: Vec<&[Offer]> = match policy {
+        Some(_) => offers.chunks(1).collect(),
+        None => vec![&offers],
+    };
+    for (turn, peers) in turns.iter().enumerate() {
+        if turn > 0 {
+            if swarm.queue.lock().unwrap().missing.is_empty() {
+                break;
+            }
+            warn!(
+                "Falling back to {} for the rest of {}",
+                peers[0].data_addr, file_name
+            );
+        }
+        // Dropping the set cancels the workers, so they never outlive the download.
+        let mut workers = JoinSet::new();
+        for offer in peers.iter() {
+            workers.spawn(fetch_pieces(
+                offer.data_addr,
+                offer.control_addr,
+                swarm.clone(),
+            ));
+        }
+        while workers.join_next().await.is_some() {}
+    }
+    let left = swarm.queue.lock().unwrap().missing.len();
+    if left > 0 {
+        return Err(Error::other(format!(
+            "{} pieces couldn't be fetched from any peer",
+            left
+        )));
+    }
+    tokio::fs::remove_file(&progress_addr).await?;
+    let digest = {
+        let part_addr = part_addr.clone();
+        task::spawn_blocking(move || digest_of(&part_addr))
+            .await
+            .map_err(Error::other)??
+    };
+    if digest != content.digest {
+        tokio::fs::remove_file(&part_addr).await?;
+        return Err(Error::other(
+            "the downloaded content doesn't match its SHA-256 digest, it was deleted",
+        ));
+    }
+    tokio::fs::rename(
+        &part_addr,
+        generate_file_address(&ctx.config, file_name, true),
+    )
+    .await?;
+    Ok(())
+}
+
+// Puts the best peer by the policy first, and the rest in the order they'd be fallen back to.
+fn rank_offers(ctx: &NodeContext, offers: &mut [Offer], policy: SourcePolicy) {
+    match policy {
+        SourcePolicy::LowestRtt => offers.sort_by_key(|offer| offer.rtt),
+        SourcePolicy::FewestComms => offers.sort_by_key(|offer| (offer.prior_comms, offer.rtt)),
+        SourcePolicy::HighestThroughput => {
+            let throughput = ctx.throughput.lock().unwrap();
+            let rate = |offer: &Offer| *throughput.get(&offer.control_addr).unwrap_or(&0.0);
+            offers.sort_by(|a, b| rate(b).total_cmp(&rate(a)).then(a.rtt.cmp(&b.rtt)));
+        }
+    }
+    for (rank, offer) in offers.iter().enumerate() {
+        info!(
+            "Candidate #{}: {} (ACK after {}ms, {} prior comms)",
+            rank + 1,
+            offer.data_addr,
+            offer.rtt.as_millis(),
+            offer.prior_comms
+        );
+    }
+}
+
+// Folds a finished piece into the peer's throughput, recent pieces weighing the most.
+fn record_throughput(ctx: &NodeContext, control_addr: SocketAddr, bytes: usize, elapsed: Duration) {
+    let rate = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+    let mut throughput = ctx.throughput.lock().unwrap();
+    let average = throughput.entry(control_addr).or_insert(rate);
+    *average = 0.75 * *average + 0.25 * rate;
+}
+
+// The piece hashes of the first peer whose list adds up to the promised Merkle root.
+async fn agreed_piece_hashes(
+    ctx: &NodeContext,
+    file_name: &str,
+    offers: &[Offer],
+    content: &Content,
+) -> std::io::Result<Vec<FileDigest>> {
+    let piece_count = content.size.div_ceil(PIECE_SIZE) as usize;
+    for offer in offers {
+        match fetch_piece_hashes(ctx, offer.control_addr, file_name, piece_count).await {
+            Ok(hashes) if merkle_root(&hashes) == content.merkle_root => return Ok(hashes),
+            Ok(_) => warn!(
+                "Piece hashes from {} don't match their Merkle root",
+                offer.control_addr
+            ),
+            Err(e) => warn!(
+                "Couldn't get piece hashes from {}: {}",
+                offer.control_addr, e
+            ),
+        }
+    }
+    Err(Error::other(
+        "no peer sent piece hashes matching its GET ACK",
+    ))
+}
+
+// Opens the partial file and the record of its finished pieces, keeping only the pieces an
+// earlier attempt finished that still pass their check.
+fn open_partial_files(
+    part_addr: &str,
+    progress_addr: &str,
+    record_header: &str,
+    file_size: u64,
+    piece_hashes: &[FileDigest],
+) -> std::io::Result<(HashSet<u64>, File, File)> {
+    if let Some(parent) = Path::new(part_addr).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let done = finished_pieces(
+        part_addr,
+        progress_addr,
+        record_header,
+        file_size,
+        piece_hashes,
+    );
+    let output = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(done.is_empty())
+        .open(part_addr)?;
+    output.set_len(file_size)?;
+    let mut progress = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(progress_addr)?;
+    // Pieces that failed their check on resume are dropped from the record as well.
+    progress.set_len(0)?;
+    writeln!(progress, "{}", record_header)?;
+    for piece in &done {
+        writeln!(progress, "{}", piece)?;
+    }
+    Ok((done, output, progress))
+}
+
+// The pieces an earlier attempt at this download finished. The record starts with the file's
+// size and digest, and a file that has changed since then has to be fetched from scratch.
+// Every piece is checked again, whatever the record says, and only those that pass are kept.
+fn finished_pieces(
+    part_addr: &str,
+    progress_addr: &str,
+    record_header: &str,
+    file_size: u64,
+    piece_hashes: &[FileDigest],
+) -> HashSet<u64> {
+    let record = match fs::read_to_string(progress_addr) {
+        Ok(record) => record,
+        Err(_) => return HashSet::new(),
+    };
+    let mut part = match File::open(part_addr) {
+        Ok(part) => part,
+        Err(_) => return HashSet::new(),
+    };
+    let part_size = part.metadata().map(|meta| meta.len()).ok();
+    let mut lines = record.lines();
+    if lines.next() != Some(record_header) || part_size != Some(file_size) {
+        return HashSet::new();
+    }
+    lines
+        .filter_map(|line| line.parse().ok())
+        .filter(|piece: &u64| {
+            let offset = piece * PIECE_SIZE;
+            let mut data = Vec::new();
+            let intact = (*piece as usize) < piece_hashes.len()
+                && part.seek(SeekFrom::Start(offset)).is_ok()
+                && (&mut part).take(PIECE_SIZE).read_to_end(&mut data).is_ok()
+                && piece_hash(&data) == piece_hashes[*piece as usize];
+            if !intact {
+                warn!(
+                    "Piece {} of the partial file is damaged, fetching it again",
+                    piece
+                );
+            }
+            intact
+        })
+        .collect()
+}
+
+// One peer's share of the swarm. A peer failing to send a piece is done for, and so is
+// one that keeps sending bad pieces; either way, another peer picks those pieces up.
+async fn fetch_pieces(peer: SocketAddr, control_addr: SocketAddr, swarm: Arc<Swarm>) {
+    let mut bad_pieces = 0;
+    loop {
+        let next = {
+            let mut queue_ptr = swarm.queue.lock().unwrap();
+            match queue_ptr.missing.pop_front() {
+                Some(piece) => {
+                    queue_ptr.in_flight += 1;
+                    Some(piece)
+                }
+                None if queue_ptr.in_flight > 0 => None,
+                None => return,
+            }
+        };
+        let piece = match next {
+            Some(piece) => piece,
+            // Someone else might still fail a piece and hand it back.
+            None => {
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let offset = piece * PIECE_SIZE;
+        let length = PIECE_SIZE.min(swarm.file_size - offset);
+        info!(
+            "Fetching piece {} of {} from {}",
+            piece, swarm.file_name, peer
+        );
+        // Whether the piece passed its hash check, if it made it here at all.
+        let started_at = Instant::now();
+        let fetched = (swarm.fetcher)(
+            swarm.ctx.clone(),
+            peer,
+            swarm.file_name.clone(),
+            offset,
+            length,
+        )
+        .await;
+        let result = match fetched {
+            Ok(data) if piece_hash(&data) == swarm.piece_hashes[piece as usize] => {
+                record_throughput(&swarm.ctx, control_addr, data.len(), started_at.elapsed());
+                let writer = swarm.clone();
+                task::spawn_blocking(move || writer.write_piece(piece, &data))
+                    .await
+                    .unwrap_or_else(|e| Err(Error::other(e)))
+                    .map(|_| true)
+            }
+            Ok(_) => Ok(false),
+            Err(e) => Err(e),
+        };
+        let mut queue_ptr = swarm.queue.lock().unwrap();
+        queue_ptr.in_flight -= 1;
+        match result {
+            Ok(true) => {}
+            Ok(false) => {
+                warn!("Piece {} from {} failed its hash check", piece, peer);
+                queue_ptr.missing.push_back(piece);
+                bad_pieces += 1;
+                if bad_pieces >= MAX_BAD_PIECES {
+                    warn!("Giving up on {}, it sent {} bad pieces", peer, bad_pieces);
+                    return;
+                }
+            }
+            Err(e) => {
+                warn!("Piece {} from {} failed: {}", piece, peer, e);
+                queue_ptr.missing.push_back(piece);
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+// Everything that can go wrong with what the network, the disk or the user hands a node.
+// None of it is a reason to take a server down, so the servers log it and move on.
+use crate::networking::MAX_WINDOW_SIZE;
+use crate::udp::headers::FrameError;
+use crate::udp::message::DecodeError;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum NetWolfError {
+    Io(io::Error),
+    // A control message that doesn't decode.
+    Decode(DecodeError),
+    // A reliable UDP frame that doesn't parse or didn't survive the trip.
+    Frame(FrameError),
+    // A node that can't be made sense of, like a bad line of the starting nodes file.
+    InvalidNode(String),
+    // An address that isn't an IPv4 address and port.
+    InvalidAddress(String),
+    UnknownConnectionType(String),
+    UnknownSourcePolicy(String),
+    // A gbn or sr window that's zero, too big or not a number.
+    InvalidWindowSize(String),
+    // Link emulation settings that don't parse.
+    InvalidLinkConditions(String),
+    // A peer expiry that's zero or not a number of seconds.
+    InvalidPeerExpiry(String),
+    // A simulation seed that's not a number.
+    InvalidSeed(String),
+    // Another task panicked while holding a lock we need.
+    Poisoned,
+}
+
+pub type Result<T> = std::result::Result<T, NetWolfError>;
+
+impl fmt::Display for NetWolfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetWolfError::Io(e) => write!(f, "{}", e),
+            NetWolfError::Decode(e) => write!(f, "{}", e),
+            NetWolfError::Frame(e) => write!(f, "{}", e),
+            NetWolfError::InvalidNode(line) => write!(f, "can't make a node out of {:?}", line),
+            NetWolfError::InvalidAddress(addr) => write!(f, "{:?} is not an IPv4 address", addr),
+            NetWolfError::UnknownConnectionType(name) => {
+                write!(f, "unknown connection type {:?}", name)
+            }
+            NetWolfError::UnknownSourcePolicy(name) => {
+                write!(f, "unknown source policy {:?}", name)
+            }
+            NetWolfError::InvalidWindowSize(window) => write!(
+                f,
+                "window size {:?} is not between 1 and {}",
+                window, MAX_WINDOW_SIZE
+            ),
+            NetWolfError::InvalidLinkConditions(settings) => {
+                write!(f, "invalid link conditions {:?}", settings)
+            }
+            NetWolfError::InvalidPeerExpiry(expiry) => {
+                write!(
+                    f,
+                    "peer expiry {:?} is not a positive number of seconds",
+                    expiry
+                )
+            }
+            NetWolfError::InvalidSeed(seed) => write!(f, "seed {:?} is not a number", seed),
+            NetWolfError::Poisoned => write!(f, "a lock was poisoned by a panicking task"),
+        }
+    }
+}
+
+impl std::error::Error for NetWolfError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            NetWolfError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NetWolfError {
+    fn from(e: io::Error) -> NetWolfError {
+        NetWolfError::Io(e)
+    }
+}
+
+impl From<DecodeError> for NetWolfError {
+    fn from(e: DecodeError) -> NetWolfError {
+        NetWolfError::Decode(e)
+    }
+}
+
+impl From<FrameError> for NetWolfError {
+    fn from(e: FrameError) -> NetWolfError {
+        NetWolfError::Frame(e)
+    }
+}
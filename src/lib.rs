@@ -0,0 +1,18 @@
+// This feature has not been stabilized yet. For more info refer to:
+// https://github.com/rust-lang/rust/issues/57563
+// #![feature(const_fn)]
+// Packet and connection names mirror their on-the-wire spelling.
+#![allow(clippy::upper_case_acronyms)]
+
+#[macro_use]
+extern crate log;
+pub mod context;
+pub mod dir;
+pub mod download;
+pub mod error;
+pub mod networking;
+pub mod node;
+pub mod search;
+pub mod sim;
+pub mod tcp;
+pub mod udp;
@@ -1,30 +1,25 @@
-// This feature has not been stabilized yet. For more info refer to:
-// https://github.com/rust-lang/rust/issues/57563
-// #![feature(const_fn)]
-
-#[macro_use]
-extern crate lazy_static;
 #[macro_use]
 extern crate log;
 extern crate simple_logger;
-mod dir;
-mod node;
-mod tcp;
-mod udp;
 use clap::{App, Arg};
-mod networking;
-use std::sync::{mpsc, RwLock};
-use std::{env, io};
+use p2p::context::{NodeConfig, NodeContext};
+use p2p::{dir, networking, node, sim, udp};
+use std::env;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
 
-lazy_static! {
-    static ref STATIC_DIR: RwLock<String> = RwLock::new(String::new());
-    static ref DATA_CONN_TYPE: RwLock<udp::headers::ConnectionType> =
-        RwLock::new(udp::headers::ConnectionType::default());
-    static ref NODE_IP: RwLock<std::net::Ipv4Addr> =
-        RwLock::new(std::net::Ipv4Addr::new(127, 0, 0, 1));
+// A flag we can't make sense of ends the program, rather than quietly running with something else.
+fn parse_or_exit<T, E: std::fmt::Display>(parsed: Result<T, E>) -> T {
+    parsed.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    })
 }
 
-fn main() -> std::io::Result<()> {
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
     env::set_var("RUST_BACKTRACE", "full");
     let matches = App::new("Netwolf?")
         .version("BROTHER")
@@ -51,6 +46,44 @@ fn main() -> std::io::Result<()> {
                 .takes_value(true)
                 .about("Pick one between tcp, sw, gbn, sr"),
         )
+        .arg(
+            Arg::with_name("window")
+                .short('w')
+                .long("window")
+                .takes_value(true)
+                .about("The sender's window size for gbn and sr, from 1 to 1024"),
+        )
+        .arg(
+            Arg::with_name("expiry")
+                .short('e')
+                .long("expiry")
+                .takes_value(true)
+                .about("Seconds of silence after which a peer is forgotten"),
+        )
+        .arg(
+            Arg::with_name("identity")
+                .long("identity")
+                .takes_value(true)
+                .about("The file keeping this node's id (defaults to .netwolf-id in the shared directory)"),
+        )
+        .arg(
+            Arg::with_name("pick")
+                .long("pick")
+                .takes_value(true)
+                .about("Download from a single peer picked by rtt, comms or throughput, instead of all of them"),
+        )
+        .arg(
+            Arg::with_name("link")
+                .long("link")
+                .takes_value(true)
+                .about("Emulate a bad link under the reliable UDP transports, like drop=0.2,dup=0.05,reorder=0.1,corrupt=0.01,delay=20"),
+        )
+        .arg(
+            Arg::with_name("simulate")
+                .long("simulate")
+                .takes_value(true)
+                .about("Replay the discovery and query flooding of a simulated network of six nodes from this seed and print what happened, no sockets involved and no transfers"),
+        )
         .arg(
             Arg::with_name("verbose")
                 .short('v')
@@ -70,32 +103,73 @@ fn main() -> std::io::Result<()> {
     let static_dir = matches.value_of("dir").unwrap_or("./static/").to_string();
     let is_verbose = matches.is_present("verbose");
     let is_local = matches.is_present("Local IP");
-    let connection_type = matches.value_of("conntype").unwrap_or_default();
-    *DATA_CONN_TYPE.write().unwrap() = match connection_type {
-        "tcp" => udp::headers::ConnectionType::TCP,
-        "sw" => udp::headers::ConnectionType::SAndW,
-        "gbn" => udp::headers::ConnectionType::GoBackN,
-        "sr" => udp::headers::ConnectionType::SRepeat,
-        &_ => udp::headers::ConnectionType::TCP,
+    let mut config = NodeConfig {
+        static_dir: static_dir.clone(),
+        ..Default::default()
     };
+    if let Some(connection_type) = matches.value_of("conntype") {
+        config.conn_type = parse_or_exit(connection_type.parse());
+    }
+    if let Some(window) = matches.value_of("window") {
+        config.window_size = parse_or_exit(networking::parse_window_size(window));
+    }
+    if let Some(expiry) = matches.value_of("expiry") {
+        config.peer_expiry = parse_or_exit(networking::parse_peer_expiry(expiry));
+    }
+    if let Some(policy) = matches.value_of("pick") {
+        config.source_policy = Some(parse_or_exit(policy.parse()));
+    }
     if is_verbose {
         simple_logger::init_with_level(log::Level::Info).unwrap();
     }
     if is_local {
-        *NODE_IP.write().unwrap() = networking::local_ip();
+        match networking::local_ip() {
+            Ok(ip) => config.ip = ip,
+            Err(e) => warn!("Can't find the local ip, staying on {}: {}", config.ip, e),
+        }
+    }
+    if let Some(link) = matches.value_of("link") {
+        config.link = parse_or_exit(udp::link::LinkConditions::parse(link));
     }
-    *STATIC_DIR.write().unwrap() = static_dir;
-    let (stdin_tx, stdin_rx) = mpsc::channel::<String>();
+    if let Some(seed) = matches.value_of("simulate") {
+        let seed = parse_or_exit(sim::parse_seed(seed));
+        let simulation = sim::demo(seed, config.link);
+        for line in simulation.trace() {
+            println!("{}", line);
+        }
+        return Ok(());
+    }
+    let default_id_file = std::path::Path::new(&static_dir).join(".netwolf-id");
+    let id_file = match matches.value_of("identity") {
+        Some(file) => file.to_string(),
+        None => default_id_file.to_string_lossy().to_string(),
+    };
+    let id = node::load_or_create_id(&id_file)?;
+    let ctx = Arc::new(NodeContext::new(config, id));
+    dir::index::open(&ctx)?;
+    let shutdown = CancellationToken::new();
+    let (watched_ctx, watch_shutdown) = (ctx.clone(), shutdown.clone());
+    let watcher =
+        tokio::task::spawn_blocking(move || dir::index::watch(watched_ctx, watch_shutdown));
+    let (stdin_tx, stdin_rx) = mpsc::unbounded_channel::<String>();
     let init_dir_string = init_nodes_dir.to_string();
-    std::thread::spawn(move || udp::main_server(init_dir_string, stdin_rx));
-    loop {
-        let mut input = String::new();
-        io::stdin().read_line(&mut input)?;
-        if input != "quit" {
-            stdin_tx.send(input.clone()).unwrap();
+    let node = tokio::spawn(udp::main_server(
+        ctx,
+        init_dir_string,
+        stdin_rx,
+        shutdown.clone(),
+    ));
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(input) = lines.next_line().await? {
+        if input.trim() != "quit" {
+            stdin_tx.send(input).unwrap();
         } else {
             break;
         }
     }
+    // Stops every server, session and download, then waits for the node to wind down.
+    shutdown.cancel();
+    let _ = node.await;
+    let _ = watcher.await;
     Ok(())
 }
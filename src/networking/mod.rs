@@ -1,36 +1,80 @@
+use crate::context::NodeContext;
+use crate::error::{self, NetWolfError};
 use crate::node;
-use crate::NODE_IP;
 use rand::Rng;
-use std::collections::HashSet;
-use std::io::{Error, ErrorKind};
-use std::net::{Ipv4Addr, UdpSocket};
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::net::Ipv4Addr;
 use std::process::Command;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 pub const CONGESTION_DELAY_MS: u64 = 500;
 pub const UDP_GET_PORT: u16 = 3222;
 pub const DISCOVERY_INTERVAL_MS: u64 = 1000;
+pub const DEFAULT_PEER_EXPIRY_SECS: u64 = 30;
 pub const BUF_SIZE: usize = 8192;
 pub const MAX_DATA_CLIENTS: u16 = 3;
 pub const PORT_MIN: u16 = 2000;
 pub const PORT_MAX: u16 = 5000;
+pub const DEFAULT_WINDOW_SIZE: u32 = 8;
+pub const MAX_WINDOW_SIZE: u32 = 1024;
+// The retransmission timeout before the sessions with a peer have their first RTT sample,
+// and the bounds the adaptive one stays within.
+pub const RDT_TIMEOUT_MS: u64 = 1000;
+pub const RDT_MIN_RTO_MS: u64 = 20;
+pub const RDT_MAX_RTO_MS: u64 = 4000;
+pub const RDT_MAX_RETRIES: u16 = 10;
+pub const GET_ACK_WINDOW_MS: u64 = 1000;
+pub const PIECE_SIZE: u64 = 64 * 1024;
+// The biggest file we'll download. Peers are taken at their word on sizes only up to here,
+// which keeps a lying one from making us allocate its piece list or a sparse file of any size.
+pub const MAX_FILE_SIZE: u64 = 64 * 1024 * 1024 * 1024;
+pub const MAX_PIECES: u64 = MAX_FILE_SIZE / PIECE_SIZE;
+pub const PIECE_HASHES_PER_MESSAGE: usize = 200;
+pub const MAX_BAD_PIECES: u32 = 3;
+// How many hops a GET or SEARCH travels, and how long its id is remembered for routing answers back.
+pub const DEFAULT_QUERY_TTL: u8 = 4;
+pub const QUERY_MEMORY_MS: u64 = 60_000;
+// A peer asking for pieces of a file it last asked for within this long is still on the same download.
+pub const DOWNLOAD_MEMORY_MS: u64 = 60_000;
+// The longest search pattern, and the most stars in it, a node sends or answers.
+pub const MAX_PATTERN_LEN: usize = 256;
+pub const MAX_PATTERN_STARS: usize = 16;
+// How much longer an emulated link holds back the datagrams it reorders.
+pub const REORDER_HOLD_MS: u64 = 50;
 
-lazy_static! {
-    pub static ref CURRENT_DATA_CLIENTS: RwLock<u16> = RwLock::new(0);
-    pub static ref DATA_SENDER_PORT: u16 = random_data_port();
-    pub static ref DATA_RECEIVER_PORT: u16 = random_data_port();
+// An empty window never sends anything, and a huge one only buffers the whole file.
+pub fn parse_window_size(window: &str) -> error::Result<u32> {
+    match window.parse() {
+        Ok(size) if (1..=MAX_WINDOW_SIZE).contains(&size) => Ok(size),
+        _ => Err(NetWolfError::InvalidWindowSize(window.to_string())),
+    }
+}
+
+// When each peer last asked for a piece of each file, by its address and the file's name.
+pub type Downloaders = Mutex<HashMap<(String, String), Instant>>;
+
+// Peers that expire right away would be forgotten between two discovery rounds.
+pub fn parse_peer_expiry(expiry: &str) -> error::Result<Duration> {
+    match expiry.parse() {
+        Ok(secs) if secs > 0 => Ok(Duration::from_secs(secs)),
+        _ => Err(NetWolfError::InvalidPeerExpiry(expiry.to_string())),
+    }
 }
 
-fn random_data_port() -> u16 {
+pub fn random_data_port() -> u16 {
     let mut r = rand::thread_rng();
     r.gen_range(PORT_MIN, PORT_MAX)
 }
 
 #[cfg(target_os = "windows")]
-pub fn local_ip() -> Ipv4Addr {
-    let output = Command::new("ipconfig").output().unwrap();
-    let output_str = String::from_utf8(output.stdout).unwrap();
+pub fn local_ip() -> error::Result<Ipv4Addr> {
+    let output = Command::new("ipconfig").output()?;
+    let output_str = String::from_utf8_lossy(&output.stdout);
     let lines = output_str.lines();
     let mut local_ipv4_string = "";
     for line in lines {
@@ -38,16 +82,27 @@ pub fn local_ip() -> Ipv4Addr {
             local_ipv4_string = line;
         }
     }
-    let a = local_ipv4_string.rfind(":").unwrap();
-    local_ipv4_string[a+2..].parse().unwrap()
+    let ip_str = local_ipv4_string
+        .rsplit(": ")
+        .next()
+        .unwrap_or_default()
+        .trim();
+    ip_str
+        .parse()
+        .map_err(|_| NetWolfError::InvalidAddress(ip_str.to_string()))
 }
 
 #[cfg(target_os = "linux")]
-pub fn local_ip() -> Ipv4Addr {
-    let output = Command::new("ipconfig").arg("-I").output().unwrap();
-    let output_str = String::from_utf8(output.stdout).unwrap();
-    let lines: Vec<&str> = output_str.split_ascii_whitespace().collect();
-    lines[lines.len() - 1].to_string().parse().unwrap()
+pub fn local_ip() -> error::Result<Ipv4Addr> {
+    let output = Command::new("ipconfig").arg("-I").output()?;
+    let output_str = String::from_utf8_lossy(&output.stdout);
+    let ip_str = output_str
+        .split_ascii_whitespace()
+        .last()
+        .unwrap_or_default();
+    ip_str
+        .parse()
+        .map_err(|_| NetWolfError::InvalidAddress(ip_str.to_string()))
 }
 
 // Wanted to put this entire sneaky node shenanigan in an inline function,
@@ -56,11 +111,11 @@ pub fn local_ip() -> Ipv4Addr {
 pub fn node_of_packet(
     nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
     addr: &str,
-) -> (node::Node, bool) {
+) -> error::Result<(node::Node, bool)> {
     let mut was_sneaky = true;
-    let mut current_node = node::Node::new_sneaky(addr);
+    let mut current_node = node::Node::new_sneaky(addr)?;
     let nodes_rwlock = nodes_arc.clone();
-    let nodes_ptr = nodes_rwlock.read().unwrap();
+    let nodes_ptr = nodes_rwlock.read().map_err(|_| NetWolfError::Poisoned)?;
     info!("Trying to unlock nodes rw");
     for node in &*nodes_ptr {
         if node.has_same_address(addr) {
@@ -69,29 +124,30 @@ pub fn node_of_packet(
             break;
         }
     }
-    (current_node, was_sneaky)
+    Ok((current_node, was_sneaky))
 }
 
 //Method one: If the requesting node has requested something before,
 pub fn delay_to_avoid_surfers(prior_comms: u16) -> u64 {
     if prior_comms > 0 {
-        return CONGESTION_DELAY_MS;
+        CONGESTION_DELAY_MS
     } else {
-        return 0;
+        0
     }
 }
 
 pub fn update_nodes(
     mut current_node: node::Node,
     nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
-) -> std::io::Result<u16> {
+) -> error::Result<u16> {
     current_node.prior_communications += 1;
+    current_node.last_seen = Instant::now();
     let nodes_rwlock = nodes_arc.clone();
     let mut nodes_ptr = match nodes_rwlock.write() {
         Ok(ptr) => ptr,
         Err(e) => {
             info!("{}", e);
-            return Err(Error::new(ErrorKind::Other, "well whatever"));
+            return Err(NetWolfError::Poisoned);
         }
     };
     info!("No problem re-adding the current node with an updated prior_comms");
@@ -103,24 +159,57 @@ pub fn update_nodes(
     Ok(prior_node_comms)
 }
 
+// Anything a known peer sends us proves it's still alive.
+pub fn mark_alive(nodes_arc: Arc<RwLock<HashSet<node::Node>>>, ip: Ipv4Addr, port: u16) {
+    let key = node::Node {
+        ip,
+        port,
+        ..Default::default()
+    };
+    let mut nodes_ptr = nodes_arc.write().unwrap();
+    if let Some(mut node) = nodes_ptr.take(&key) {
+        node.last_seen = Instant::now();
+        nodes_ptr.insert(node);
+    }
+}
+
+// Every piece of a file is a data GET of its own, but only the first one of a download counts
+// as a communication. The count returned is of the downloads before this one.
 pub fn check_clients(
+    ctx: &NodeContext,
     ip: Ipv4Addr,
     port: u16,
+    file_name: &str,
     nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
-) -> (bool, u16) {
+) -> error::Result<(bool, u16)> {
     let stream_addr = ip_port_string(ip, port);
     info!("Accepted Client: {}", &stream_addr);
-    let (current_node, was_sneaky) = node_of_packet(nodes_arc.clone(), &stream_addr);
-    let prior_comms = update_nodes(current_node, nodes_arc.clone()).unwrap();
-    (was_sneaky, prior_comms)
+    let (current_node, was_sneaky) = node_of_packet(nodes_arc.clone(), &stream_addr)?;
+    let comms = if starts_download(ctx, &stream_addr, file_name) {
+        update_nodes(current_node, nodes_arc.clone())?
+    } else {
+        current_node.prior_communications
+    };
+    Ok((was_sneaky, comms.saturating_sub(1)))
+}
+
+// Whether the peer hasn't asked for any of the file for a while, remembering that it just did.
+fn starts_download(ctx: &NodeContext, requester: &str, file_name: &str) -> bool {
+    let memory = Duration::from_millis(DOWNLOAD_MEMORY_MS);
+    let now = Instant::now();
+    let mut downloaders = ctx.downloaders.lock().unwrap();
+    downloaders.retain(|_, asked_at| now.saturating_duration_since(*asked_at) < memory);
+    downloaders
+        .insert((requester.to_string(), file_name.to_string()), now)
+        .is_none()
 }
 
 pub fn ip_port_string(ip: Ipv4Addr, port: u16) -> String {
     format!("{}:{}", ip, port)
 }
 
-pub fn update_client_number(increment: bool) {
-    let mut current_clients_ptr = CURRENT_DATA_CLIENTS.write().unwrap();
+pub fn update_client_number(ctx: &NodeContext, increment: bool) {
+    let mut current_clients_ptr = ctx.data_clients.write().unwrap();
     if increment {
         *current_clients_ptr += 1;
     } else {
@@ -128,21 +217,28 @@ pub fn update_client_number(increment: bool) {
     }
 }
 
-pub fn bind_udp_socket(mut port: u16, with_timeout: bool) -> UdpSocket {
+pub async fn bind_udp_socket(ip: Ipv4Addr, mut port: u16) -> UdpSocket {
     loop {
-        let udp_server_addr = ip_port_string(*NODE_IP.read().unwrap(), port);
-        match UdpSocket::bind(udp_server_addr) {
-            Ok(sckt) => {
-                let timeout: Duration = Duration::new(1, 0);
-                if with_timeout {
-                    sckt.set_write_timeout(Some(timeout)).unwrap();
-                    sckt.set_read_timeout(Some(timeout)).unwrap();
-                }
-                return sckt;
-            }
-            Err(_) => (),
-        };
+        let udp_server_addr = ip_port_string(ip, port);
+        if let Ok(sckt) = UdpSocket::bind(udp_server_addr).await {
+            return sckt;
+        }
         // Try another port if the previous port failed
         port += 1;
     }
 }
+
+// Runs a task until it's done or the node shuts down, whichever comes first.
+// Whatever the task was waiting on when it's cancelled is simply dropped.
+pub fn spawn_until<F>(shutdown: &CancellationToken, task: F) -> JoinHandle<()>
+where
+    F: Future + Send + 'static,
+{
+    let shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = shutdown.cancelled() => {}
+            _ = task => {}
+        }
+    })
+}
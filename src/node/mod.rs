@@ -1,18 +1,42 @@
+use crate::error::{self, NetWolfError};
 use crate::networking;
-use crate::udp::headers::PacketHeader;
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use std::collections::HashSet;
-use std::net::Ipv4Addr;
+use std::hash::{Hash, Hasher};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use std::{fmt, fs};
-// Make sure to read from an LF file!
 
-#[derive(Clone, Hash, Eq, PartialEq, Debug)]
+#[derive(Clone, Debug)]
 pub struct Node {
     pub name: String,
     pub ip: Ipv4Addr,
     pub port: u16,
     pub prior_communications: u16,
+    // When we (or whoever gossiped it to us) last heard from this node.
+    pub last_seen: Instant,
+    // The node's own persistent identifier, or UNKNOWN_ID until it tells us.
+    pub id: u128,
+}
+
+pub const UNKNOWN_ID: u128 = 0;
+
+// Two entries with the same address are the same peer, whatever else we know about them.
+impl PartialEq for Node {
+    fn eq(&self, other: &Node) -> bool {
+        self.ip == other.ip && self.port == other.port
+    }
+}
+
+impl Eq for Node {}
+
+impl Hash for Node {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.ip.hash(state);
+        self.port.hash(state);
+    }
 }
 
 impl fmt::Display for Node {
@@ -28,89 +52,142 @@ impl Default for Node {
             ip: Ipv4Addr::new(0, 0, 0, 0),
             port: 0,
             prior_communications: 0,
+            last_seen: Instant::now(),
+            id: UNKNOWN_ID,
         }
     }
 }
 
 impl Node {
-    pub fn new(name_str: &str, ip_str: &str, port: u16) -> Node {
-        let ip_parsed = str_to_u8_vector(ip_str);
+    pub fn new(name_str: &str, ip: Ipv4Addr, port: u16) -> Node {
         Node {
             name: String::from(name_str),
-            ip: Ipv4Addr::new(ip_parsed[0], ip_parsed[1], ip_parsed[2], ip_parsed[3]),
+            ip,
             port,
             ..Default::default()
         }
     }
 
-    pub fn nodes_to_string(nodes: &HashSet<Node>) -> String {
-        let mut nodes_string = String::from(PacketHeader::discovery());
-        for node in nodes {
-            let a = node.to_string();
-            nodes_string.push_str(&a);
-            // For distinction in deserializing
-            nodes_string.push('\n');
-        }
-        nodes_string.truncate(nodes_string.trim_end().len());
-        nodes_string
+    pub fn short_id(&self) -> String {
+        format!("{:08x}", (self.id >> 96) as u32)
+    }
+
+    pub fn age(&self) -> Duration {
+        self.age_at(Instant::now())
+    }
+
+    pub fn age_at(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_seen)
     }
 
     pub fn has_same_address(&self, other_str: &str) -> bool {
-        self.to_short_string() == other_str.to_string()
+        self.to_short_string() == other_str
     }
 
     pub fn to_short_string(&self) -> String {
         networking::ip_port_string(self.ip, self.port)
     }
 
-    pub fn multiple_from_string(data: String, trim: bool) -> HashSet<Node> {
+    // Lines that aren't nodes are skipped, blank ones silently.
+    pub fn multiple_from_string(data: String) -> HashSet<Node> {
         let mut nodes: HashSet<Node> = HashSet::new();
-        let split_by_line = data.split("\n");
-        // Skip header!
-        if trim {
-            for line in split_by_line.skip(1) {
-                Node::insert_single_from_string(line, &mut nodes);
-            }
-        } else {
-            for line in split_by_line {
-                Node::insert_single_from_string(line, &mut nodes);
+        for line in data.lines().filter(|line| !line.trim().is_empty()) {
+            match line.parse() {
+                Ok(node) => {
+                    nodes.insert(node);
+                }
+                Err(e) => warn!("Skipping a starting node: {}", e),
             }
         }
         nodes
     }
 
-    fn insert_single_from_string(line: &str, nodes: &mut HashSet<Node>) {
-        let node_strs: Vec<&str> = line.split(" ").collect();
-        // #communications with this new node is zero!
-        let node = Node::new(
-            node_strs[0],
-            node_strs[1],
-            node_strs[2].parse::<u16>().unwrap(),
-        );
-        nodes.insert(node);
-    }
-
-    pub fn new_sneaky(line: &str) -> Node {
-        let node_strs: Vec<&str> = line.split(":").collect();
+    // Whoever sent us something from an address we don't know.
+    pub fn new_sneaky(addr: &str) -> error::Result<Node> {
+        let addr = SocketAddrV4::from_str(addr)
+            .map_err(|_| NetWolfError::InvalidAddress(addr.to_string()))?;
         let mut name = String::from("Sneaky-");
         let rand_string: String = thread_rng().sample_iter(&Alphanumeric).take(8).collect();
         name.push_str(&rand_string);
-        Node::new(&name, node_strs[0], node_strs[1].parse::<u16>().unwrap())
+        Ok(Node::new(&name, *addr.ip(), addr.port()))
+    }
+}
+
+// A line of the starting nodes file: `name ip port`.
+impl FromStr for Node {
+    type Err = NetWolfError;
+
+    fn from_str(line: &str) -> error::Result<Node> {
+        let invalid = || NetWolfError::InvalidNode(line.to_string());
+        match line.split_whitespace().collect::<Vec<&str>>()[..] {
+            [name, ip, port] => {
+                let ip = ip.parse().map_err(|_| invalid())?;
+                let port = port.parse().map_err(|_| invalid())?;
+                // #communications with this new node is zero!
+                Ok(Node::new(name, ip, port))
+            }
+            _ => Err(invalid()),
+        }
     }
 }
 
-fn str_to_u8_vector(ip_str: &str) -> Vec<u8> {
-    let mut ip_parsed: Vec<u8> = Vec::new();
-    let split_by_dot = ip_str.split(".");
-    for ip_part in split_by_dot {
-        let ip_part_string = String::from(ip_part);
-        let ip_part_u8 = ip_part_string.parse::<u8>().unwrap();
-        ip_parsed.push(ip_part_u8);
+// Folds what we just heard about a peer into what we already knew. Known ids win over
+// addresses, so a peer that came back on another port keeps its history.
+pub fn merge_node(nodes: &mut HashSet<Node>, heard: Node) {
+    let by_id = if heard.id != UNKNOWN_ID {
+        nodes.iter().find(|node| node.id == heard.id).cloned()
+    } else {
+        None
+    };
+    let known = match by_id.or_else(|| nodes.get(&heard).cloned()) {
+        Some(known) => known,
+        None => {
+            nodes.insert(heard);
+            return;
+        }
+    };
+    nodes.remove(&known);
+    let (ip, port) = if heard.last_seen > known.last_seen {
+        (heard.ip, heard.port)
+    } else {
+        (known.ip, known.port)
+    };
+    let merged = Node {
+        name: if known.name.is_empty() {
+            heard.name
+        } else {
+            known.name
+        },
+        ip,
+        port,
+        prior_communications: known.prior_communications,
+        last_seen: known.last_seen.max(heard.last_seen),
+        id: if known.id != UNKNOWN_ID {
+            known.id
+        } else {
+            heard.id
+        },
+    };
+    // Whoever used to sit on the new address is gone now.
+    nodes.replace(merged);
+}
+
+// Reads this node's identifier from disk, or makes one up and saves it for next time.
+pub fn load_or_create_id(id_file: &str) -> std::io::Result<u128> {
+    if let Ok(stored) = fs::read_to_string(id_file) {
+        if let Ok(id) = u128::from_str_radix(stored.trim(), 16) {
+            return Ok(id);
+        }
+    }
+    let mut id = UNKNOWN_ID;
+    while id == UNKNOWN_ID {
+        id = rand::random();
     }
-    ip_parsed
+    fs::write(id_file, format!("{:032x}", id))?;
+    Ok(id)
 }
 
-pub fn read_starting_nodes(file_dir: &str) -> HashSet<Node> {
-    let data = fs::read_to_string(file_dir).expect("Something's wrong with the file.");
-    return Node::multiple_from_string(data, false);
+pub fn read_starting_nodes(file_dir: &str) -> error::Result<HashSet<Node>> {
+    let data = fs::read_to_string(file_dir)?;
+    Ok(Node::multiple_from_string(data))
 }
@@ -0,0 +1,237 @@
+use crate::context::NodeContext;
+use crate::dir::{hex_digest, index, FileDigest};
+use crate::networking::{BUF_SIZE, GET_ACK_WINDOW_MS, MAX_PATTERN_LEN, MAX_PATTERN_STARS};
+use crate::udp::message::Message;
+use log::info;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::timeout;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SearchHit {
+    pub file_name: String,
+    pub size: u64,
+    pub digest: FileDigest,
+}
+
+pub enum SearchEvent {
+    // The user searched for something, so results for it are welcome for a while.
+    Started(String),
+    // A peer answered a search with (some of) its matching files.
+    Found {
+        pattern: String,
+        peer: SocketAddr,
+        hits: Vec<SearchHit>,
+    },
+}
+
+// One line of the numbered list the user picks from with `get #N`.
+struct AggregatedHit {
+    hit: SearchHit,
+    peers: Vec<SocketAddr>,
+}
+
+struct PendingSearch {
+    started_at: Instant,
+    hits: Vec<AggregatedHit>,
+}
+
+// A hit's name length, size and digest, before the name itself.
+const MIN_HIT_SIZE: usize = 2 + 8 + 32;
+
+// The list of the last search, as it was printed.
+pub type LastResults = RwLock<Vec<SearchHit>>;
+
+// Patterns with `*` or `?` are globs over the whole path, anything else is a substring.
+// Both ignore case.
+pub fn matches(pattern: &str, file_name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let file_name = file_name.to_lowercase();
+    if pattern.contains(['*', '?']) {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let file_name: Vec<char> = file_name.chars().collect();
+        glob_matches(&pattern, &file_name)
+    } else {
+        file_name.contains(&pattern)
+    }
+}
+
+// Only ever backtracks to the last `*` seen, so it stays O(pattern * text) however many stars
+// a peer packs into its pattern.
+fn glob_matches(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Where the last star was, and how much of the text it has swallowed so far.
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((star_p, star_t)) => {
+                    star = Some((star_p, star_t + 1));
+                    p = star_p + 1;
+                    t = star_t + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+// Patterns come off the network and get flooded on, so they're kept short and cheap to match.
+pub fn valid_pattern(pattern: &str) -> bool {
+    pattern.len() <= MAX_PATTERN_LEN && pattern.matches('*').count() <= MAX_PATTERN_STARS
+}
+
+pub fn local_hits(ctx: &NodeContext, pattern: &str) -> Vec<SearchHit> {
+    index::file_names(ctx)
+        .into_iter()
+        .filter(|file_name| matches(pattern, file_name))
+        .filter_map(|file_name| {
+            let entry = index::lookup(ctx, &file_name)?;
+            Some(SearchHit {
+                file_name,
+                size: entry.hashes.size,
+                digest: entry.hashes.digest,
+            })
+        })
+        .collect()
+}
+
+// The same search for the async side of the node, kept off its runtime
+// since without a watcher every match may have to be hashed first.
+pub async fn local_hits_async(ctx: &Arc<NodeContext>, pattern: &str) -> Vec<SearchHit> {
+    let ctx = ctx.clone();
+    let pattern = pattern.to_string();
+    tokio::task::spawn_blocking(move || local_hits(&ctx, &pattern))
+        .await
+        .unwrap_or_default()
+}
+
+// Splits the hits into as many answers as it takes for each one to fit in a datagram.
+// Nothing comes back for a pattern that leaves no room for hits.
+pub fn result_messages(query_id: u128, pattern: &str, hits: Vec<SearchHit>) -> Vec<Message> {
+    // Leave room for the header, the query id, the responder, the pattern and the hit count.
+    let budget = match (BUF_SIZE - 30).checked_sub(pattern.len()) {
+        Some(budget) if budget >= MIN_HIT_SIZE => budget,
+        _ => {
+            info!(
+                "No room to answer a search for a {} byte pattern",
+                pattern.len()
+            );
+            return Vec::new();
+        }
+    };
+    let answer = |hits| Message::SearchResults {
+        query_id,
+        // The first hop knows our address better than we do.
+        responder: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        pattern: pattern.to_string(),
+        hits,
+    };
+    let mut messages = Vec::new();
+    let mut chunk: Vec<SearchHit> = Vec::new();
+    let mut chunk_size = 0;
+    for hit in hits {
+        let hit_size = MIN_HIT_SIZE + hit.file_name.len();
+        if hit_size > budget {
+            info!("{} doesn't fit in a search answer", hit.file_name);
+            continue;
+        }
+        if !chunk.is_empty() && chunk_size + hit_size > budget {
+            messages.push(answer(std::mem::take(&mut chunk)));
+            chunk_size = 0;
+        }
+        chunk_size += hit_size;
+        chunk.push(hit);
+    }
+    if !chunk.is_empty() {
+        messages.push(answer(chunk));
+    }
+    messages
+}
+
+// What `get #N` refers to, counting from one.
+pub fn numbered_hit(ctx: &NodeContext, number: usize) -> Option<SearchHit> {
+    ctx.last_results
+        .read()
+        .unwrap()
+        .get(number.checked_sub(1)?)
+        .cloned()
+}
+
+// Collects the answers to every search for a while, then prints them as one numbered list.
+// Peers holding the very same content show up as a single line.
+pub async fn search_manager(ctx: Arc<NodeContext>, mut receiver: UnboundedReceiver<SearchEvent>) {
+    let answer_window = Duration::from_millis(GET_ACK_WINDOW_MS);
+    let mut pending: HashMap<String, PendingSearch> = HashMap::new();
+    loop {
+        match timeout(answer_window / 10, receiver.recv()).await {
+            Ok(Some(SearchEvent::Started(pattern))) => {
+                pending.insert(
+                    pattern,
+                    PendingSearch {
+                        started_at: Instant::now(),
+                        hits: Vec::new(),
+                    },
+                );
+            }
+            Ok(Some(SearchEvent::Found {
+                pattern,
+                peer,
+                hits,
+            })) => match pending.get_mut(&pattern) {
+                Some(search) => {
+                    for hit in hits {
+                        match search.hits.iter_mut().find(|known| known.hit == hit) {
+                            Some(known) if !known.peers.contains(&peer) => known.peers.push(peer),
+                            Some(_) => {}
+                            None => search.hits.push(AggregatedHit {
+                                hit,
+                                peers: vec![peer],
+                            }),
+                        }
+                    }
+                }
+                None => info!("Ignoring search results for {} from {}", pattern, peer),
+            },
+            Err(_) => {}
+            Ok(None) => return,
+        }
+        let finished: Vec<String> = pending
+            .iter()
+            .filter(|(_, search)| search.started_at.elapsed() >= answer_window)
+            .map(|(pattern, _)| pattern.clone())
+            .collect();
+        for pattern in finished {
+            let mut hits = pending.remove(&pattern).unwrap().hits;
+            if hits.is_empty() {
+                println!("Nothing matches {}", pattern);
+                continue;
+            }
+            hits.sort_by(|a, b| a.hit.file_name.cmp(&b.hit.file_name));
+            for (i, aggregated) in hits.iter().enumerate() {
+                println!(
+                    "#{} {} ({} bytes, sha256 {}, {} peers)",
+                    i + 1,
+                    aggregated.hit.file_name,
+                    aggregated.hit.size,
+                    &hex_digest(&aggregated.hit.digest)[..16],
+                    aggregated.peers.len()
+                );
+            }
+            *ctx.last_results.write().unwrap() =
+                hits.into_iter().map(|aggregated| aggregated.hit).collect();
+        }
+    }
+}
@@ -0,0 +1,579 @@
+// A whole network of NetWolf nodes in a single thread, with no sockets and no wall clock.
+//
+// Every datagram is an event that arrives some virtual milliseconds after it was sent, and
+// time only moves from one event to the next, so a minute of gossip takes no time at all.
+// Every random choice, from latencies and link impairments to node and query ids, comes
+// from one rng seeded up front: the same seed and the same setup replay the same run, event
+// for event, and the trace it leaves is how a failing run is told apart and reproduced.
+//
+// This is only part of the simulator we're after, which would run the node's own tasks over
+// virtual sockets and a virtual clock. What's here is the control plane, discovery and GETs
+// and SEARCHes flooded out and answered back, and not even the real one: `discover` and
+// `arrive` are a copy of what `discovery_server` and `get_server` do with a round or a
+// message. They're built from the same pieces, `absorb_discovery`, `discovery_messages`, the
+// routes of `udp::query`, `get_ack` and `search::result_messages`, encoded and decoded like on
+// the wire, but how those tasks tie the pieces together isn't tested here, and a change to it
+// has to be made here as well. Nothing is ever downloaded, since the transports, the swarm and
+// its Merkle checks only run on real sockets and tokio's clock. The network harness of the
+// tests is the place for those until the real tasks run here.
+// The link conditions apply to every datagram, as there are no data frames to save them for.
+use crate::dir::merkle::{self, FileHashes};
+use crate::error::{self, NetWolfError};
+use crate::networking::{
+    ip_port_string, DEFAULT_PEER_EXPIRY_SECS, DEFAULT_QUERY_TTL, DISCOVERY_INTERVAL_MS,
+    UDP_GET_PORT,
+};
+use crate::node::Node;
+use crate::search::{self, SearchHit};
+use crate::udp::link::LinkConditions;
+use crate::udp::message::Message;
+use crate::udp::{self, query};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BTreeMap, BinaryHeap, HashSet};
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::{Duration, Instant};
+
+// Every hop takes between 1 and this many ms, on top of whatever the link adds.
+const MAX_LATENCY_MS: u64 = 10;
+// Virtual nodes live on 10.0.0.1, 10.0.0.2 and so on.
+const FIRST_ADDRESS: u32 = 0x0a00_0001;
+// What a virtual node says its data socket is in its GET ACKs, nothing ever listens on it.
+const DATA_PORT: u16 = UDP_GET_PORT + 1;
+
+enum Event {
+    Arrival {
+        from: SocketAddr,
+        to: SocketAddr,
+        datagram: Vec<u8>,
+    },
+    // A node's next round of discovery.
+    Discovery(usize),
+    Command(usize, String),
+}
+
+// Events at the same time happen in the order they were scheduled.
+struct Scheduled {
+    at: u64,
+    order: u64,
+    event: Event,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Scheduled) -> bool {
+        (self.at, self.order) == (other.at, other.order)
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Scheduled) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Scheduled) -> Ordering {
+        (self.at, self.order).cmp(&(other.at, other.order))
+    }
+}
+
+pub struct SimNode {
+    pub name: String,
+    pub id: u128,
+    ip: Ipv4Addr,
+    // The node's own list of the peers it knows.
+    pub peers: HashSet<Node>,
+    routes: query::Routes,
+    // The discoveries heard since the last round.
+    heard: Vec<(Node, Vec<Node>)>,
+    files: BTreeMap<String, FileHashes>,
+    // Who answered our GETs, by file name, and what our SEARCHes turned up, by pattern.
+    offers: BTreeMap<String, Vec<SocketAddr>>,
+    found: BTreeMap<String, Vec<(SocketAddr, SearchHit)>>,
+}
+
+impl SimNode {
+    pub fn control_addr(&self) -> SocketAddr {
+        SocketAddr::from((self.ip, UDP_GET_PORT))
+    }
+
+    pub fn knows(&self, other: &SimNode) -> bool {
+        self.peers
+            .iter()
+            .any(|peer| peer.ip == other.ip && peer.port == UDP_GET_PORT)
+    }
+
+    // The control addresses of the peers that ACK'd a GET of ours for the file.
+    pub fn offers(&self, file_name: &str) -> &[SocketAddr] {
+        self.offers.get(file_name).map_or(&[], |offers| &offers[..])
+    }
+
+    // Every hit a SEARCH of ours for the pattern got back, and who it came from.
+    pub fn found(&self, pattern: &str) -> &[(SocketAddr, SearchHit)] {
+        self.found.get(pattern).map_or(&[], |found| &found[..])
+    }
+
+    // Iterating a HashSet is up to its random hasher, so whatever we send goes in address order.
+    fn sorted_peers(&self) -> Vec<Node> {
+        let mut peers: Vec<Node> = self.peers.iter().cloned().collect();
+        peers.sort_by_key(|peer| (peer.ip, peer.port));
+        peers
+    }
+}
+
+pub struct Simulation {
+    seed: u64,
+    rng: StdRng,
+    link: LinkConditions,
+    // Virtual time is this instant plus `now_ms`, only ever compared with itself.
+    epoch: Instant,
+    now_ms: u64,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    scheduled: u64,
+    nodes: Vec<SimNode>,
+    trace: Vec<String>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, link: LinkConditions) -> Simulation {
+        Simulation {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            link,
+            epoch: Instant::now(),
+            now_ms: 0,
+            queue: BinaryHeap::new(),
+            scheduled: 0,
+            nodes: Vec::new(),
+            trace: Vec::new(),
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn now_ms(&self) -> u64 {
+        self.now_ms
+    }
+
+    pub fn nodes(&self) -> &[SimNode] {
+        &self.nodes
+    }
+
+    pub fn node(&self, node: usize) -> &SimNode {
+        &self.nodes[node]
+    }
+
+    // One line per thing that happened, the same lines for the same seed.
+    pub fn trace(&self) -> &[String] {
+        &self.trace
+    }
+
+    // Nodes start their discovery rounds at random points of the first interval.
+    pub fn add_node(&mut self) -> usize {
+        let index = self.nodes.len();
+        let mut id = 0;
+        while id == 0 {
+            id = self.rng.gen();
+        }
+        self.nodes.push(SimNode {
+            name: format!("N{}", index),
+            id,
+            ip: Ipv4Addr::from(FIRST_ADDRESS + index as u32),
+            peers: HashSet::new(),
+            routes: Default::default(),
+            heard: Vec::new(),
+            files: BTreeMap::new(),
+            offers: BTreeMap::new(),
+            found: BTreeMap::new(),
+        });
+        let first_round = self.rng.gen_range(0, DISCOVERY_INTERVAL_MS);
+        self.schedule(first_round, Event::Discovery(index));
+        index
+    }
+
+    // Both nodes start out knowing each other, like with a line in each one's nodes file.
+    pub fn connect(&mut self, a: usize, b: usize) {
+        let now = self.now();
+        for (node, peer) in [(a, b), (b, a)] {
+            let mut known = Node::new(&self.nodes[peer].name, self.nodes[peer].ip, UDP_GET_PORT);
+            known.last_seen = now;
+            self.nodes[node].peers.insert(known);
+        }
+    }
+
+    // Hashed like a shared file on disk, so GET ACKs carry the same digests a real node's would.
+    pub fn share(&mut self, node: usize, file_name: &str, contents: &[u8]) {
+        let hashes = merkle::hash_contents(contents).unwrap();
+        self.nodes[node].files.insert(file_name.to_string(), hashes);
+    }
+
+    // Has the node run a command, like `get a.txt` or `search *.txt`, at a virtual time.
+    pub fn command(&mut self, node: usize, at_ms: u64, line: &str) {
+        let after = at_ms.saturating_sub(self.now_ms);
+        self.schedule(after, Event::Command(node, line.to_string()));
+    }
+
+    pub fn random_bytes(&mut self, size: usize) -> Vec<u8> {
+        (0..size).map(|_| self.rng.gen()).collect()
+    }
+
+    // Plays every event up to the given virtual time.
+    pub fn run_until(&mut self, end_ms: u64) {
+        while let Some(Reverse(next)) = self.queue.peek() {
+            if next.at > end_ms {
+                break;
+            }
+            let Reverse(next) = self.queue.pop().unwrap();
+            self.now_ms = next.at;
+            match next.event {
+                Event::Arrival { from, to, datagram } => self.arrive(from, to, datagram),
+                Event::Discovery(node) => self.discover(node),
+                Event::Command(node, line) => self.run_command(node, &line),
+            }
+        }
+        self.now_ms = self.now_ms.max(end_ms);
+    }
+
+    fn now(&self) -> Instant {
+        self.epoch + Duration::from_millis(self.now_ms)
+    }
+
+    fn schedule(&mut self, after_ms: u64, event: Event) {
+        self.scheduled += 1;
+        self.queue.push(Reverse(Scheduled {
+            at: self.now_ms + after_ms,
+            order: self.scheduled,
+            event,
+        }));
+    }
+
+    fn log(&mut self, node: usize, what: String) {
+        let line = format!("{:>8}ms {:<4} {}", self.now_ms, self.nodes[node].name, what);
+        self.trace.push(line);
+    }
+
+    fn node_at(&self, addr: SocketAddr) -> Option<usize> {
+        let ip = match addr {
+            SocketAddr::V4(addr) => u32::from(*addr.ip()),
+            SocketAddr::V6(_) => return None,
+        };
+        let index = ip.checked_sub(FIRST_ADDRESS)? as usize;
+        if index < self.nodes.len() {
+            Some(index)
+        } else {
+            None
+        }
+    }
+
+    fn send(&mut self, from: SocketAddr, to: SocketAddr, datagram: Vec<u8>) {
+        let fate = self.link.fate(&datagram, &mut self.rng);
+        if fate.copies == 0 {
+            if let Some(node) = self.node_at(from) {
+                let what = format!("lost {} to {}", self.describe(&fate.datagram), to);
+                self.log(node, what);
+            }
+        }
+        let delay = fate.delay.as_millis() as u64;
+        for _ in 0..fate.copies {
+            let latency = self.rng.gen_range(1, MAX_LATENCY_MS + 1) + delay;
+            let datagram = fate.datagram.clone();
+            self.schedule(latency, Event::Arrival { from, to, datagram });
+        }
+    }
+
+    // The trace names messages without anything that differs from run to run.
+    fn describe(&self, datagram: &[u8]) -> String {
+        match Message::decode_at(datagram, self.now()) {
+            Ok(Message::Discovery { nodes, .. }) => format!("DISCOVERY of {} nodes", nodes.len()),
+            Ok(Message::Get {
+                query_id,
+                ttl,
+                file_name,
+            }) => format!("GET {:08x} ttl {} {}", short(query_id), ttl, file_name),
+            Ok(Message::GetAck {
+                query_id,
+                responder,
+                file_name,
+                ..
+            }) => format!(
+                "GET ACK {:08x} by {} {}",
+                short(query_id),
+                responder,
+                file_name
+            ),
+            Ok(Message::Search {
+                query_id,
+                ttl,
+                pattern,
+            }) => format!("SEARCH {:08x} ttl {} {}", short(query_id), ttl, pattern),
+            Ok(Message::SearchResults {
+                query_id,
+                responder,
+                hits,
+                ..
+            }) => format!(
+                "SEARCH RESULTS {:08x} by {} with {} hits",
+                short(query_id),
+                responder,
+                hits.len()
+            ),
+            Ok(_) => String::from("some other message"),
+            Err(e) => format!("garbage ({})", e),
+        }
+    }
+
+    // What `get_server` does with a datagram, keep the two in step.
+    fn arrive(&mut self, from: SocketAddr, to: SocketAddr, datagram: Vec<u8>) {
+        let node = match self.node_at(to) {
+            Some(node) => node,
+            None => return,
+        };
+        let what = format!("<- {} {}", from, self.describe(&datagram));
+        self.log(node, what);
+        let now = self.now();
+        let message = match Message::decode_at(&datagram, now) {
+            Ok(message) => message,
+            Err(_) => return,
+        };
+        let sender_ip = match from {
+            SocketAddr::V4(from) => *from.ip(),
+            SocketAddr::V6(_) => return,
+        };
+        // Anything a known peer sends us proves it's still alive.
+        let key = Node::new("", sender_ip, from.port());
+        if let Some(mut peer) = self.nodes[node].peers.take(&key) {
+            peer.last_seen = now;
+            self.nodes[node].peers.insert(peer);
+        }
+        match &message {
+            Message::Discovery { sender_id, nodes } => {
+                let sender = Node {
+                    ip: sender_ip,
+                    port: from.port(),
+                    id: *sender_id,
+                    last_seen: now,
+                    ..Default::default()
+                };
+                self.nodes[node].heard.push((sender, nodes.clone()));
+            }
+            Message::Get {
+                query_id,
+                file_name,
+                ..
+            } => {
+                if !self.first_sighting(node, *query_id, from) {
+                    return;
+                }
+                if let Some(forwarded) = query::next_hop(&message) {
+                    self.flood(node, &forwarded, Some(from));
+                }
+                let response = match self.nodes[node].files.get(file_name) {
+                    Some(hashes) => udp::get_ack(*query_id, DATA_PORT, hashes, file_name),
+                    None => return,
+                };
+                self.reply(node, from, &response);
+            }
+            Message::Search {
+                query_id, pattern, ..
+            } => {
+                if !search::valid_pattern(pattern) || !self.first_sighting(node, *query_id, from) {
+                    return;
+                }
+                if let Some(forwarded) = query::next_hop(&message) {
+                    self.flood(node, &forwarded, Some(from));
+                }
+                let hits = self.nodes[node]
+                    .files
+                    .iter()
+                    .filter(|(file_name, _)| search::matches(pattern, file_name))
+                    .map(|(file_name, hashes)| SearchHit {
+                        file_name: file_name.clone(),
+                        size: hashes.size,
+                        digest: hashes.digest,
+                    })
+                    .collect();
+                for response in search::result_messages(*query_id, pattern, hits) {
+                    self.reply(node, from, &response);
+                }
+            }
+            Message::GetAck {
+                query_id,
+                responder,
+                ..
+            }
+            | Message::SearchResults {
+                query_id,
+                responder,
+                ..
+            } => {
+                let responder = udp::responder_addr(responder, from);
+                match query::route_in(&self.nodes[node].routes, *query_id) {
+                    Some(query::Route::Origin) => self.answered(node, message, responder.into()),
+                    Some(query::Route::Upstream(upstream)) => {
+                        if let Some(forwarded) = query::relayed(&message, responder) {
+                            self.reply(node, upstream, &forwarded);
+                        }
+                    }
+                    None => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn first_sighting(&mut self, node: usize, query_id: u128, sender: SocketAddr) -> bool {
+        let route = query::Route::Upstream(sender);
+        query::remember_at(&self.nodes[node].routes, query_id, route, self.now())
+    }
+
+    fn reply(&mut self, node: usize, to: SocketAddr, message: &Message) {
+        let own_addr = self.nodes[node].control_addr();
+        if let Some(datagram) = self.encode(node, message) {
+            self.send(own_addr, to, datagram);
+        }
+    }
+
+    fn encode(&mut self, node: usize, message: &Message) -> Option<Vec<u8>> {
+        match message.encode_at(self.now()) {
+            Ok(datagram) => Some(datagram),
+            Err(e) => {
+                self.log(node, format!("can't send: {}", e));
+                None
+            }
+        }
+    }
+
+    // An answer to one of our own queries, which is as far as the simulation takes it.
+    fn answered(&mut self, node: usize, answer: Message, responder: SocketAddr) {
+        match answer {
+            Message::GetAck { file_name, .. } => {
+                let offers = self.nodes[node]
+                    .offers
+                    .entry(file_name.clone())
+                    .or_default();
+                if offers.contains(&responder) {
+                    return;
+                }
+                offers.push(responder);
+                self.log(node, format!("{} offers {}", responder, file_name));
+            }
+            Message::SearchResults { pattern, hits, .. } => {
+                let found = self.nodes[node].found.entry(pattern.clone()).or_default();
+                for hit in hits {
+                    if !found.contains(&(responder, hit.clone())) {
+                        found.push((responder, hit));
+                    }
+                }
+                let what = format!("{} results for {} so far", found.len(), pattern);
+                self.log(node, what);
+            }
+            _ => {}
+        }
+    }
+
+    // Passes a message on to every peer but the one it came from.
+    fn flood(&mut self, node: usize, message: &Message, sender: Option<SocketAddr>) {
+        let own_addr = self.nodes[node].control_addr();
+        let datagram = match self.encode(node, message) {
+            Some(datagram) => datagram,
+            None => return,
+        };
+        for peer in self.nodes[node].sorted_peers() {
+            let peer_addr = SocketAddr::from((peer.ip, peer.port));
+            if Some(peer_addr) != sender {
+                self.send(own_addr, peer_addr, datagram.clone());
+            }
+        }
+    }
+
+    // A round of `discovery_server`, keep the two in step.
+    fn discover(&mut self, node: usize) {
+        let now = self.now();
+        let sim_node = &mut self.nodes[node];
+        let heard = mem::take(&mut sim_node.heard);
+        let local_address = ip_port_string(sim_node.ip, UDP_GET_PORT);
+        udp::absorb_discovery(
+            &mut sim_node.peers,
+            heard,
+            &local_address,
+            sim_node.id,
+            Duration::from_secs(DEFAULT_PEER_EXPIRY_SECS),
+            now,
+        );
+        for discovery in udp::discovery_messages(sim_node.id, &sim_node.sorted_peers()) {
+            self.flood(node, &discovery, None);
+        }
+        self.schedule(DISCOVERY_INTERVAL_MS, Event::Discovery(node));
+    }
+
+    fn run_command(&mut self, node: usize, line: &str) {
+        self.log(node, format!("$ {}", line));
+        let (command, argument) = line.split_once(' ').unwrap_or((line, ""));
+        let argument = argument.trim().to_string();
+        let query_id = self.rng.gen();
+        let request = match command {
+            "get" if !argument.is_empty() => Message::Get {
+                query_id,
+                ttl: DEFAULT_QUERY_TTL,
+                file_name: argument,
+            },
+            "search" if search::valid_pattern(&argument) && !argument.is_empty() => {
+                Message::Search {
+                    query_id,
+                    ttl: DEFAULT_QUERY_TTL,
+                    pattern: argument,
+                }
+            }
+            _ => {
+                let what =
+                    String::from("the simulator only knows `get <file>` and `search <pattern>`");
+                self.log(node, what);
+                return;
+            }
+        };
+        let now = self.now();
+        query::remember_at(
+            &self.nodes[node].routes,
+            query_id,
+            query::Route::Origin,
+            now,
+        );
+        self.flood(node, &request, None);
+    }
+}
+
+fn short(query_id: u128) -> u32 {
+    (query_id >> 96) as u32
+}
+
+pub fn parse_seed(seed: &str) -> error::Result<u64> {
+    seed.parse()
+        .map_err(|_| NetWolfError::InvalidSeed(seed.to_string()))
+}
+
+// A line of six nodes, each knowing only its neighbours. The first one shares a few files and,
+// once the network had a few seconds to find itself, the last one looks for them.
+pub fn demo(seed: u64, link: LinkConditions) -> Simulation {
+    let mut simulation = Simulation::new(seed, link);
+    for _ in 0..6 {
+        simulation.add_node();
+    }
+    for node in 1..simulation.nodes.len() {
+        simulation.connect(node - 1, node);
+    }
+    for file_name in ["blob.bin", "notes.txt", "music/song.mp3"] {
+        let contents = simulation.random_bytes(200_000);
+        simulation.share(0, file_name, &contents);
+    }
+    let last = simulation.nodes.len() - 1;
+    simulation.command(last, 5_000, "get blob.bin");
+    simulation.command(last, 6_000, "search *.txt");
+    simulation.run_until(20_000);
+    simulation
+}
@@ -1,37 +1,45 @@
-use crate::dir::{file_list, generate_file_address};
+use crate::context::NodeContext;
+use crate::dir::{index, open_range, FileRange};
 use crate::networking::{
-    check_clients, delay_to_avoid_surfers, ip_port_string, update_client_number, UDP_GET_PORT
+    check_clients, delay_to_avoid_surfers, ip_port_string, spawn_until, update_client_number,
+    BUF_SIZE,
 };
 use crate::node;
-use crate::NODE_IP;
-use crate::udp::headers::{PacketHeader, TCPHeader};
+use crate::udp::message::Message;
 use log::{info, warn};
 use std::collections::HashSet;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::{Arc, RwLock};
-use std::{thread, time};
-use crate::networking::{self, BUF_SIZE};
+use std::time;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::sync::CancellationToken;
 
-// This function is not yet compliant with its corresponding TCP sender.
-pub fn tcp_client(addr: SocketAddr, file_name: String) -> std::io::Result<()> {
+pub async fn tcp_client(
+    ctx: Arc<NodeContext>,
+    addr: SocketAddr,
+    file_name: String,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<Vec<u8>> {
     info!("Trying to connect to socket: {}", addr);
-    let file_addr = generate_file_address(&file_name, true);
-    let mut stream = TcpStream::connect(addr)?;
-    let request_header = TCPHeader::new(PacketHeader::TCPGET, UDP_GET_PORT, file_name);
-    stream.write(request_header.to_string().as_bytes()).unwrap();
-    stream.shutdown(Shutdown::Write).unwrap();
-    let mut tcp_input_stream = BufReader::new(stream);
-    info!("Trying to create the receiving file for writing");
-    let f = File::create(file_addr)?;
-    let mut file_output_stream = BufWriter::new(f);
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = Message::TcpGet {
+        get_port: ctx.control_port(),
+        offset,
+        length: Some(length),
+        file_name,
+    };
+    stream.write_all(&request.encode()?).await?;
+    stream.shutdown().await?;
     info!("Starting to receive data from TCP socket");
-    handle_both(&mut tcp_input_stream, &mut file_output_stream, 0)
+    let mut received = Vec::with_capacity(length as usize);
+    stream.take(length).read_to_end(&mut received).await?;
+    Ok(received)
 }
 
-pub fn handle_both<T: Read, U: Write>(
-    input: &mut BufReader<T>,
+pub async fn handle_both<T: AsyncRead + Unpin, U: AsyncWrite + Unpin>(
+    input: &mut T,
     output: &mut BufWriter<U>,
     delay: u64,
 ) -> std::io::Result<()> {
@@ -39,68 +47,110 @@ pub fn handle_both<T: Read, U: Write>(
     let mut size: usize = 1;
     let anti_surfing_interval = time::Duration::from_millis(delay);
     while size > 0 {
-        size = input.read(&mut buf)?;
-        output.write(&buf[..size])?;
-        thread::sleep(anti_surfing_interval);
+        size = input.read(&mut buf).await?;
+        output.write_all(&buf[..size]).await?;
+        tokio::time::sleep(anti_surfing_interval).await;
         info!("Read and Wrote {} bytes from/to sockets", size);
     }
+    // Unlike std's, tokio's buffers aren't flushed when they're dropped.
+    output.shutdown().await?;
     info!("Finished reading and writing!");
     Ok(())
 }
 
-pub fn handle_client(stream: TcpStream, file_name: String, delay: u64) -> std::io::Result<()> {
+pub async fn handle_client(
+    ctx: &NodeContext,
+    stream: TcpStream,
+    mut file_range: FileRange,
+    delay: u64,
+) -> std::io::Result<()> {
     let mut tcp_output_steam = BufWriter::new(stream);
-    let file_addr = generate_file_address(&file_name, false);
-    // let b = stream.local_addr();
-    let f = File::open(file_addr)?;
-    let mut file_input_stream = BufReader::new(f);
-    update_client_number(true);
-    let result = handle_both(&mut file_input_stream, &mut tcp_output_steam, delay);
-    update_client_number(false);
+    update_client_number(ctx, true);
+    let result = handle_both(&mut file_range, &mut tcp_output_steam, delay).await;
+    update_client_number(ctx, false);
     result
 }
 
-fn check_and_handle_clients(mut stream: TcpStream, nodes_arc: Arc<RwLock<HashSet<node::Node>>>) {
-    let mut tcp_get_packet = String::new();
-    stream.read_to_string(&mut tcp_get_packet).unwrap_or(0);
-    let data_header = TCPHeader::from_string(tcp_get_packet);
-    if data_header.conn_type == PacketHeader::TCPGET {
-        // If old node, it's ok; if not, check again!
-        let (was_sneaky, prior_comms) = check_clients(
-            match stream.peer_addr().unwrap().ip() {
-                IpAddr::V4(v4) => v4,
-                IpAddr::V6(_) => return,
-            },
-            data_header.udp_get_port,
-            nodes_arc,
-        );
-        if !was_sneaky || file_list().iter().any(|x| x == &data_header.file_name) {
-            std::thread::spawn(move || {
-                handle_client(
-                    stream,
-                    data_header.file_name,
-                    delay_to_avoid_surfers(prior_comms),
-                )
-            });
+async fn check_and_handle_clients(
+    ctx: Arc<NodeContext>,
+    mut stream: TcpStream,
+    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+) {
+    let mut tcp_get_packet = Vec::new();
+    stream.read_to_end(&mut tcp_get_packet).await.unwrap_or(0);
+    match Message::decode(&tcp_get_packet) {
+        Ok(Message::TcpGet {
+            get_port,
+            offset,
+            length,
+            file_name,
+        }) => {
+            let peer_ip = match stream.peer_addr().map(|addr| addr.ip()) {
+                Ok(IpAddr::V4(v4)) => v4,
+                _ => return,
+            };
+            // If old node, it's ok; if not, check again!
+            let (was_sneaky, prior_comms) =
+                match check_clients(&ctx, peer_ip, get_port, &file_name, nodes_arc) {
+                    Ok(pair) => pair,
+                    Err(e) => {
+                        warn!("Refused Client: {}", e);
+                        return;
+                    }
+                };
+            if !was_sneaky || index::lookup_async(&ctx, &file_name).await.is_some() {
+                let file_range = match open_range(&ctx.config, &file_name, offset, length).await {
+                    Ok(range) => range,
+                    Err(e) => {
+                        warn!("Can't serve {}: {}", file_name, e);
+                        return;
+                    }
+                };
+                let delay = delay_to_avoid_surfers(prior_comms);
+                if let Err(e) = handle_client(&ctx, stream, file_range, delay).await {
+                    warn!("Couldn't finish sending {}: {}", file_name, e);
+                }
+            }
+        }
+        Ok(_) => {
+            // Malicious packets BTFO
+            warn!("Refused malicious Client");
+            drop(stream);
+        }
+        Err(e) => {
+            warn!("Refused Client: {}", e);
+            drop(stream);
         }
-    } else {
-        // Malicious packets BTFO
-        warn!("Refused malicious Client");
-        drop(stream);
     }
 }
 
 // First packet of every stream: Who you are and what you want (again)
 // Because all sending is done through this one TCP Listener.
-pub fn tcp_server(nodes_arc: Arc<RwLock<HashSet<node::Node>>>) -> std::io::Result<()> {
-    let tcp_addr = ip_port_string(*NODE_IP.read().unwrap(), *networking::DATA_SENDER_PORT);
-    let listener = match TcpListener::bind(&tcp_addr) {
+// Every client is served by a task of its own, and all of them stop when the node shuts down.
+pub async fn tcp_server(
+    ctx: Arc<NodeContext>,
+    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let tcp_addr = ip_port_string(ctx.config.ip, ctx.config.data_sender_port);
+    let listener = match TcpListener::bind(&tcp_addr).await {
         Ok(lsner) => lsner,
         Err(_) => return Ok(()),
     };
-    info!("Opened TCP Socket on: {}", tcp_addr);
-    for stream in listener.incoming() {
-        check_and_handle_clients(stream?, nodes_arc.clone());
+    ctx.set_data_port(listener.local_addr()?.port());
+    info!("Opened TCP Socket on: {}", listener.local_addr()?);
+    loop {
+        // A client that gave up before we got to it is no reason to stop serving the rest.
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!("Couldn't accept a client: {}", e);
+                continue;
+            }
+        };
+        spawn_until(
+            &shutdown,
+            check_and_handle_clients(ctx.clone(), stream, nodes_arc.clone()),
+        );
     }
-    Ok(())
 }
@@ -1,30 +1,43 @@
-use std::convert::TryInto;
+use crate::error::NetWolfError;
 use std::fmt;
 use std::mem::size_of;
+use std::str::FromStr;
 
 pub const RDT_HEADER_SIZE: u16 = 3;
+pub const RDT_FRAME_VERSION: u8 = 2;
+pub const FIN_FLAG: u8 = 0b0000_0001;
+// Tag, version, flags, session, sequence number, payload length and checksum.
+pub const FRAME_HEADER_SIZE: usize =
+    RDT_HEADER_SIZE as usize + 2 * size_of::<u8>() + 2 * size_of::<u32>() + 2 * size_of::<u16>();
+const CHECKSUM_OFFSET: usize = FRAME_HEADER_SIZE - size_of::<u16>();
 
+#[derive(Clone, Copy, Default)]
 pub enum ConnectionType {
+    #[default]
     TCP,
     SAndW,
     GoBackN,
     SRepeat,
 }
 
-impl Default for ConnectionType {
-    fn default() -> ConnectionType {
-        ConnectionType::TCP
+impl FromStr for ConnectionType {
+    type Err = NetWolfError;
+
+    fn from_str(name: &str) -> Result<ConnectionType, NetWolfError> {
+        match name {
+            "tcp" => Ok(ConnectionType::TCP),
+            "sw" => Ok(ConnectionType::SAndW),
+            "gbn" => Ok(ConnectionType::GoBackN),
+            "sr" => Ok(ConnectionType::SRepeat),
+            _ => Err(NetWolfError::UnknownConnectionType(name.to_string())),
+        }
     }
 }
 
+// Tags of the reliable UDP data frames. The control plane lives in udp::message.
 #[derive(PartialEq, Eq, Debug)]
 pub enum PacketHeader {
-    Disc,
-    GET,
-    GETACK,
-    TCPGET,
-    RDTGET,
-    RDTEND,
+    StopWaitData,
     StopWaitACK,
     StopWaitNAK,
     GoBackN,
@@ -34,27 +47,9 @@ pub enum PacketHeader {
 
 impl PacketHeader {
     // So apparently const functions work without even enabling the feature!
-    // These three have an \n in the front because they're going to be
-    // Parsed as strings, but the others should be treated as raw bytes.
-    pub const fn discovery() -> &'static str {
-        "DISC\n"
-    }
-    pub const fn ack() -> &'static str {
-        "ACK\n"
-    }
-    pub const fn get() -> &'static str {
-        "GET\n"
-    }
-    pub const fn tcp_get() -> &'static str {
-        "TCPGET"
-    }
-
     // All of the following should be of size 3.
-    pub const fn rdt_get() -> &'static str {
-        "RDT"
-    }
-    pub const fn rdt_end() -> &'static str {
-        "END"
+    pub const fn stop_and_wait_data() -> &'static str {
+        "SWD"
     }
     pub const fn stop_and_wait_ack() -> &'static str {
         "SWA"
@@ -69,37 +64,16 @@ impl PacketHeader {
         "SER"
     }
 
-    pub fn is_end(buf: &[u8]) -> bool {
-        let ans = std::str::from_utf8(buf).unwrap_or("");
-        ans.starts_with(PacketHeader::rdt_end())
-    }
-
-    // TODO: Check each packet header for UDP, only the first packet for TCP.
-    pub fn packet_type(packet_str: &str) -> PacketHeader {
+    pub fn packet_type(header: &str) -> PacketHeader {
         // Doing this repetitive work because the following PR has not been merged as of today:
         // https://github.com/rust-lang/rfcs/pull/2920
-        const DISCOVERY: &'static str = PacketHeader::discovery();
-        const GET: &'static str = PacketHeader::get();
-        const ACK: &'static str = PacketHeader::ack();
-        const TCP_GET: &'static str = PacketHeader::tcp_get();
-        const STOP_AND_WAIT_ACK: &'static str = PacketHeader::stop_and_wait_ack();
-        const STOP_AND_WAIT_NAK: &'static str = PacketHeader::stop_and_wait_nak();
-        const GO_BACK_N: &'static str = PacketHeader::go_back_n();
-        const SELECTIVE_REPEAT: &'static str = PacketHeader::selective_repeat();
-        const RDT: &'static str = PacketHeader::rdt_get();
-        const END: &'static str = PacketHeader::rdt_end();
-        let header_str = packet_str.lines().next().unwrap_or("");
-        let header = [header_str, "\n"].join("");
-        if header.starts_with(DISCOVERY) {
-            PacketHeader::Disc
-        } else if header.starts_with(GET) {
-            PacketHeader::GET
-        } else if header.starts_with(ACK) {
-            PacketHeader::GETACK
-        } else if header.starts_with(TCP_GET) {
-            PacketHeader::TCPGET
-        } else if header.starts_with(END) {
-            PacketHeader::RDTEND
+        const STOP_AND_WAIT_DATA: &str = PacketHeader::stop_and_wait_data();
+        const STOP_AND_WAIT_ACK: &str = PacketHeader::stop_and_wait_ack();
+        const STOP_AND_WAIT_NAK: &str = PacketHeader::stop_and_wait_nak();
+        const GO_BACK_N: &str = PacketHeader::go_back_n();
+        const SELECTIVE_REPEAT: &str = PacketHeader::selective_repeat();
+        if header.starts_with(STOP_AND_WAIT_DATA) {
+            PacketHeader::StopWaitData
         } else if header.starts_with(STOP_AND_WAIT_ACK) {
             PacketHeader::StopWaitACK
         } else if header.starts_with(STOP_AND_WAIT_NAK) {
@@ -108,8 +82,6 @@ impl PacketHeader {
             PacketHeader::GoBackN
         } else if header.starts_with(SELECTIVE_REPEAT) {
             PacketHeader::SRepeat
-        } else if header.starts_with(RDT) {
-            PacketHeader::RDTGET
         } else {
             PacketHeader::Unrecognized
         }
@@ -118,69 +90,28 @@ impl PacketHeader {
 
 impl fmt::Display for PacketHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut display_str: &'static str = "";
-        if self == &PacketHeader::Disc {
-            display_str = PacketHeader::discovery();
-        } else if self == &PacketHeader::GET {
-            display_str = PacketHeader::discovery();
-        } else if self == &PacketHeader::GETACK {
-            display_str = PacketHeader::ack();
-        } else if self == &PacketHeader::TCPGET {
-            display_str = PacketHeader::tcp_get();
+        let display_str: &'static str = if self == &PacketHeader::StopWaitData {
+            PacketHeader::stop_and_wait_data()
         } else if self == &PacketHeader::StopWaitACK {
-            display_str = PacketHeader::stop_and_wait_ack();
+            PacketHeader::stop_and_wait_ack()
         } else if self == &PacketHeader::StopWaitNAK {
-            display_str = PacketHeader::stop_and_wait_nak();
+            PacketHeader::stop_and_wait_nak()
         } else if self == &PacketHeader::GoBackN {
-            display_str = PacketHeader::go_back_n();
+            PacketHeader::go_back_n()
         } else if self == &PacketHeader::SRepeat {
-            display_str = PacketHeader::selective_repeat();
-        } else if self == &PacketHeader::RDTGET {
-            display_str = PacketHeader::rdt_get();
-        } else if self == &PacketHeader::RDTEND {
-            display_str = PacketHeader::rdt_end();
+            PacketHeader::selective_repeat()
         } else {
-            display_str = PacketHeader::discovery();
-        }
+            "???"
+        };
         write!(f, "{}", display_str)
     }
 }
 
-pub struct TCPHeader {
-    pub conn_type: PacketHeader,
-    pub udp_get_port: u16,
-    pub file_name: String,
-}
-
-impl TCPHeader {
-    pub fn new(conn_type: PacketHeader, udp_get_port: u16, file_name: String) -> TCPHeader {
-        TCPHeader {
-            conn_type,
-            udp_get_port,
-            file_name,
-        }
-    }
-
-    pub fn from_string(packet: String) -> TCPHeader {
-        let mut packet_lines = packet.lines();
-        let packet_type = packet_lines.next().unwrap();
-        let conn_type = PacketHeader::packet_type(&packet_type);
-        let udp_get_port = packet_lines.next().unwrap().parse::<u16>().unwrap_or(0);
-        let file_name = packet_lines.next().unwrap_or("").to_string();
-        TCPHeader::new(conn_type, udp_get_port, file_name)
-    }
-
-    pub fn to_string(&self) -> String {
-        format!(
-            "{}\n{}\n{}",
-            self.conn_type, self.udp_get_port, self.file_name
-        )
-    }
-}
-
+#[allow(dead_code)]
 pub enum StdinHeader {
     LIST,
     GET,
+    SEARCH,
 }
 
 impl StdinHeader {
@@ -190,65 +121,148 @@ impl StdinHeader {
     pub fn list() -> &'static str {
         "list"
     }
+    pub fn search() -> &'static str {
+        "search"
+    }
 }
 
-pub struct StopAndWaitHeader {
+// Every data packet, ACK and NAK of the reliable UDP transports is one of these frames,
+// all fields big-endian:
+//
+// | tag (3) | version (1) | flags (1) | session (4) | seq (4) | length (2) | checksum (2) |
+// | payload (length) |
+//
+// The tag names the transport (SWD/SWA/SWN, GBN or SER) so the data servers can dispatch
+// on it, the session is the one the RDT GET that started the transfer named, and the
+// checksum covers the whole frame with its own field zeroed.
+pub struct FrameHeader {
     pub header_type: PacketHeader,
-    pub header_size: u16,
-    pub get_port: u16,
-    pub file_name: String,
+    pub version: u8,
+    pub flags: u8,
+    pub session: u32,
+    pub seq: u32,
+    pub length: u16,
+    pub checksum: u16,
 }
 
-impl StopAndWaitHeader {
-    pub fn new(
-        header_type: PacketHeader,
-        get_port: u16,
-        file_name: &str,
-    ) -> StopAndWaitHeader {
-        StopAndWaitHeader {
+#[derive(Debug)]
+pub enum FrameError {
+    Truncated,
+    Version(u8),
+    Corrupted,
+    // Intact, but left over from another transfer.
+    Session(u32),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FrameError::Truncated => write!(f, "frame is truncated"),
+            FrameError::Version(version) => write!(
+                f,
+                "frame of version {}, we speak {}",
+                version, RDT_FRAME_VERSION
+            ),
+            FrameError::Corrupted => write!(f, "frame failed its checksum"),
+            FrameError::Session(session) => write!(f, "frame of another session ({})", session),
+        }
+    }
+}
+
+impl FrameHeader {
+    pub fn new(header_type: PacketHeader, session: u32, seq: u32) -> FrameHeader {
+        FrameHeader {
             header_type,
-            header_size: StopAndWaitHeader::find_header_size(&file_name),
-            get_port,
-            file_name: String::from(file_name),
+            version: RDT_FRAME_VERSION,
+            flags: 0,
+            session,
+            seq,
+            length: 0,
+            checksum: 0,
         }
     }
 
-    pub fn from_bytes(buf: &[u8]) -> (StopAndWaitHeader, &[u8]) {
+    // The last frame of a transfer, carrying no data.
+    pub fn fin(header_type: PacketHeader, session: u32, seq: u32) -> FrameHeader {
+        FrameHeader {
+            flags: FIN_FLAG,
+            ..FrameHeader::new(header_type, session, seq)
+        }
+    }
+
+    pub fn is_fin(&self) -> bool {
+        self.flags & FIN_FLAG != 0
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<(FrameHeader, &[u8]), FrameError> {
+        if buf.len() < FRAME_HEADER_SIZE {
+            return Err(FrameError::Truncated);
+        }
         let base = RDT_HEADER_SIZE as usize;
-        let header = PacketHeader::packet_type(std::str::from_utf8(&buf[..base]).unwrap_or(""));
-        let size = size_of::<u16>();
-        let header_size_bytes: [u8; 2] = buf[base..base + size].try_into().unwrap();
-        let header_size = u16::from_ne_bytes(header_size_bytes) as usize;
-        let get_port_bytes: [u8; 2] = buf[base + size..base + size * 2].try_into().unwrap();
-        let get_port = u16::from_ne_bytes(get_port_bytes);
-        let file_name = std::str::from_utf8(&buf[base + size * 2..header_size])
-            .unwrap()
-            .to_string();
-        (
-            StopAndWaitHeader::new(header, get_port, &file_name),
-            &buf[header_size..],
-        )
-    }
-
-    pub fn as_string(&self) -> String {
-        let mut header_str = String::new();
-        header_str.push_str(&self.header_type.to_string());
-        header_str.push_str(&self.header_size.to_string());
-        header_str.push_str(&self.get_port.to_string());
-        header_str.push_str(&self.file_name);
-        header_str
-    }
-
-    pub fn find_header_size(file_name: &str) -> u16 {
-        RDT_HEADER_SIZE + (size_of::<u16>() as u16) * 2 + file_name.as_bytes().len() as u16
+        let header_type =
+            PacketHeader::packet_type(std::str::from_utf8(&buf[..base]).unwrap_or(""));
+        let version = buf[base];
+        if version != RDT_FRAME_VERSION {
+            return Err(FrameError::Version(version));
+        }
+        let u32_at =
+            |at: usize| u32::from_be_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]]);
+        let header = FrameHeader {
+            header_type,
+            version,
+            flags: buf[base + 1],
+            session: u32_at(base + 2),
+            seq: u32_at(base + 6),
+            length: u16::from_be_bytes([buf[base + 10], buf[base + 11]]),
+            checksum: u16::from_be_bytes([buf[CHECKSUM_OFFSET], buf[CHECKSUM_OFFSET + 1]]),
+        };
+        let payload = &buf[FRAME_HEADER_SIZE..];
+        if payload.len() != header.length as usize {
+            return Err(FrameError::Truncated);
+        }
+        // Recompute the checksum with its own field zeroed, like the sender did.
+        let mut zeroed = buf.to_vec();
+        zeroed[CHECKSUM_OFFSET..FRAME_HEADER_SIZE].copy_from_slice(&[0, 0]);
+        if internet_checksum(&zeroed) != header.checksum {
+            return Err(FrameError::Corrupted);
+        }
+        Ok((header, payload))
     }
 
     pub fn as_vec(&self) -> Vec<u8> {
+        self.with_payload(&[])
+    }
+
+    pub fn with_payload(&self, payload: &[u8]) -> Vec<u8> {
         let type_str = self.header_type.to_string();
-        let type_bytes = type_str.as_bytes();
-        let size_bytes = self.header_size.to_ne_bytes();
-        let get_port_bytes = self.get_port.to_ne_bytes();
-        let file_name_bytes = self.file_name.as_bytes();
-        [type_bytes, &size_bytes, &get_port_bytes, file_name_bytes].concat()
+        let length = payload.len() as u16;
+        let mut packet = [
+            type_str.as_bytes(),
+            &[self.version, self.flags],
+            &self.session.to_be_bytes(),
+            &self.seq.to_be_bytes(),
+            &length.to_be_bytes(),
+            &[0, 0],
+            payload,
+        ]
+        .concat();
+        let checksum = internet_checksum(&packet);
+        packet[CHECKSUM_OFFSET..FRAME_HEADER_SIZE].copy_from_slice(&checksum.to_be_bytes());
+        packet
+    }
+}
+
+// The 16-bit one's complement checksum of RFC 1071, same as the one in IP, UDP and TCP headers.
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    for chunk in data.chunks(2) {
+        let word = match chunk {
+            [high, low] => u16::from_be_bytes([*high, *low]),
+            [high] => u16::from_be_bytes([*high, 0]),
+            _ => 0,
+        };
+        sum += word as u32;
+        sum = (sum & 0xffff) + (sum >> 16);
     }
+    !(sum as u16)
 }
@@ -0,0 +1,156 @@
+// A UDP socket that can pretend to be a bad link, so the reliable transports can be seen
+// surviving loss on a single box. Only what we send is impaired, so a link that's bad both
+// ways needs both ends to be set up that way.
+//
+// Every outgoing datagram may, independently:
+// * be dropped,
+// * have one of its bits flipped,
+// * be sent twice,
+// * be held back long enough for the ones after it to overtake it,
+// on top of the fixed delay every datagram gets.
+use crate::error::{self, NetWolfError};
+use crate::networking::REORDER_HOLD_MS;
+use rand::{thread_rng, Rng};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::sleep;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConditions {
+    pub drop_rate: f64,
+    pub duplicate_rate: f64,
+    pub reorder_rate: f64,
+    pub corrupt_rate: f64,
+    pub delay: Duration,
+}
+
+impl LinkConditions {
+    // Comma-separated settings like `drop=0.2,dup=0.05,reorder=0.1,corrupt=0.01,delay=20`,
+    // rates between 0 and 1 and the delay in ms. Whatever is left out stays perfect.
+    pub fn parse(settings: &str) -> error::Result<LinkConditions> {
+        let invalid = || NetWolfError::InvalidLinkConditions(settings.to_string());
+        let mut conditions = LinkConditions::default();
+        for setting in settings.split(',').filter(|setting| !setting.is_empty()) {
+            let (name, value) = setting.split_once('=').ok_or_else(invalid)?;
+            let rate = match name.trim() {
+                "drop" => &mut conditions.drop_rate,
+                "dup" => &mut conditions.duplicate_rate,
+                "reorder" => &mut conditions.reorder_rate,
+                "corrupt" => &mut conditions.corrupt_rate,
+                "delay" => {
+                    let ms = value.trim().parse().map_err(|_| invalid())?;
+                    conditions.delay = Duration::from_millis(ms);
+                    continue;
+                }
+                _ => return Err(invalid()),
+            };
+            *rate = value.trim().parse().map_err(|_| invalid())?;
+            if !(0.0..=1.0).contains(rate) {
+                return Err(invalid());
+            }
+        }
+        Ok(conditions)
+    }
+
+    pub fn is_perfect(&self) -> bool {
+        *self == LinkConditions::default()
+    }
+
+    // Rolls the dice for one datagram. The simulator brings its own seeded rng.
+    pub fn fate<R: Rng + ?Sized>(&self, datagram: &[u8], rng: &mut R) -> Fate {
+        let copies = if rng.gen_bool(self.drop_rate) {
+            0
+        } else if rng.gen_bool(self.duplicate_rate) {
+            2
+        } else {
+            1
+        };
+        let mut delay = self.delay;
+        if rng.gen_bool(self.reorder_rate) {
+            delay += Duration::from_millis(REORDER_HOLD_MS);
+        }
+        let mut datagram = datagram.to_vec();
+        if rng.gen_bool(self.corrupt_rate) && !datagram.is_empty() {
+            let bit = rng.gen_range(0, datagram.len() * 8);
+            datagram[bit / 8] ^= 1 << (bit % 8);
+        }
+        Fate {
+            copies,
+            datagram,
+            delay,
+        }
+    }
+}
+
+// What happens to one datagram on its way out: how many copies of what arrive, how late.
+pub struct Fate {
+    pub copies: usize,
+    pub datagram: Vec<u8>,
+    pub delay: Duration,
+}
+
+pub struct LinkSocket {
+    socket: Arc<UdpSocket>,
+    conditions: LinkConditions,
+}
+
+impl LinkSocket {
+    pub fn new(socket: UdpSocket, conditions: LinkConditions) -> LinkSocket {
+        LinkSocket {
+            socket: Arc::new(socket),
+            conditions,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub async fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.recv(buf).await
+    }
+
+    pub async fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.socket.recv_from(buf).await
+    }
+
+    // Sends to the peer the socket is connected to.
+    pub async fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.send_impaired(buf, None).await
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        self.send_impaired(buf, Some(target)).await
+    }
+
+    // A lost datagram still counts as sent, like it would on a real link.
+    async fn send_impaired(&self, buf: &[u8], target: Option<SocketAddr>) -> io::Result<usize> {
+        if self.conditions.is_perfect() {
+            return send(&self.socket, buf, target).await;
+        }
+        let fate = self.conditions.fate(buf, &mut thread_rng());
+        for _ in 0..fate.copies {
+            if fate.delay.is_zero() {
+                send(&self.socket, &fate.datagram, target).await?;
+                continue;
+            }
+            let (socket, datagram, delay) =
+                (self.socket.clone(), fate.datagram.clone(), fate.delay);
+            tokio::spawn(async move {
+                sleep(delay).await;
+                let _ = send(&socket, &datagram, target).await;
+            });
+        }
+        Ok(buf.len())
+    }
+}
+
+async fn send(socket: &UdpSocket, buf: &[u8], target: Option<SocketAddr>) -> io::Result<usize> {
+    match target {
+        Some(target) => socket.send_to(buf, target).await,
+        None => socket.send(buf).await,
+    }
+}
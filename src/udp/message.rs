@@ -0,0 +1,513 @@
+// The control plane: everything that isn't a reliable UDP data frame or raw TCP file data.
+//
+// Every message is laid out as follows, all integers big-endian:
+//
+// | version (1) | kind (1) | body |
+//
+// where the body of each kind is:
+//
+// * 1 Discovery: sender id (16), count (2), then `count` times:
+//                name (str), ip (4), port (2), age in ms (4), id (16)
+// * 2 Get:       query id (16), ttl (1), file name (str)
+// * 3 GetAck:    query id (16), responder (6), data port (2), file size (8), SHA-256 digest (32),
+//                Merkle root (32), file name (str)
+// * 4 TcpGet:    UDP GET port (2), offset (8), length (opt), file name (str)
+// * 5 RdtGet:    UDP GET port (2), session (4), offset (8), length (opt), file name (str)
+// * 6 GetPieces: first piece (4), file name (str)
+// * 7 Pieces:    first piece (4), count (2), `count` piece hashes (32 each), file name (str)
+// * 8 Search:    query id (16), ttl (1), pattern (str)
+// * 9 SearchResults: query id (16), responder (6), pattern (str), count (2), then `count` times:
+//                file name (str), file size (8), SHA-256 digest (32)
+//
+// a str is its byte length (2) followed by that many bytes of UTF-8,
+// and an opt is a presence flag (1) followed by the value (8) only if the flag is 1.
+// File names are paths relative to the shared directory, '/'-separated, like `music/a.mp3`.
+// A peer's age is how long ago the sender last heard from it, so gossip can't make dead peers look alive.
+// Data GETs ask for a byte range of the file, so a download can be split among peers
+// or resumed; without a length the range runs to the end of the file.
+// A reliable UDP data GET names a random session, and every frame of the transfer it starts
+// carries it, so frames left over from an earlier transfer between the same ports are told apart.
+// Piece hashes don't fit in a single GET ACK for big files, so they're asked for a chunk at a time.
+// Search results are split the same way, each chunk answering the pattern on its own.
+// GETs and SEARCHes are flooded: each node forwards them with one less ttl, and answers carry
+// the query id back along the path the query came. A responder is an ip (4) and control port (2),
+// 0.0.0.0:0 when it's the sender itself, and the first hop fills it in from the source address.
+// Ids are the persistent 128-bit node identifiers, zero when the sender doesn't know it yet.
+// Peers speaking any other version are rejected rather than guessed at.
+// Nothing is ever cut short to fit: a str or count that outgrows its two bytes, or a message
+// that outgrows a datagram, fails to encode, and it's up to the sender to split it up.
+use crate::dir::FileDigest;
+use crate::networking::BUF_SIZE;
+use crate::node::Node;
+use crate::search::SearchHit;
+use std::convert::TryFrom;
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::{Duration, Instant};
+
+pub const PROTOCOL_VERSION: u8 = 12;
+
+const DISCOVERY: u8 = 1;
+const GET: u8 = 2;
+const GET_ACK: u8 = 3;
+const TCP_GET: u8 = 4;
+const RDT_GET: u8 = 5;
+const GET_PIECES: u8 = 6;
+const PIECES: u8 = 7;
+const SEARCH: u8 = 8;
+const SEARCH_RESULTS: u8 = 9;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Message {
+    Discovery {
+        sender_id: u128,
+        nodes: Vec<Node>,
+    },
+    Get {
+        query_id: u128,
+        ttl: u8,
+        file_name: String,
+    },
+    GetAck {
+        query_id: u128,
+        responder: SocketAddrV4,
+        data_port: u16,
+        file_size: u64,
+        digest: FileDigest,
+        merkle_root: FileDigest,
+        file_name: String,
+    },
+    TcpGet {
+        get_port: u16,
+        offset: u64,
+        length: Option<u64>,
+        file_name: String,
+    },
+    RdtGet {
+        get_port: u16,
+        session: u32,
+        offset: u64,
+        length: Option<u64>,
+        file_name: String,
+    },
+    GetPieces {
+        first: u32,
+        file_name: String,
+    },
+    Pieces {
+        first: u32,
+        hashes: Vec<FileDigest>,
+        file_name: String,
+    },
+    Search {
+        query_id: u128,
+        ttl: u8,
+        pattern: String,
+    },
+    SearchResults {
+        query_id: u128,
+        responder: SocketAddrV4,
+        pattern: String,
+        hits: Vec<SearchHit>,
+    },
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    Version(u8),
+    UnknownKind(u8),
+    Truncated,
+    InvalidString,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Version(version) => write!(
+                f,
+                "peer speaks protocol version {}, we speak {}",
+                version, PROTOCOL_VERSION
+            ),
+            DecodeError::UnknownKind(kind) => write!(f, "unknown message kind {}", kind),
+            DecodeError::Truncated => write!(f, "message is truncated"),
+            DecodeError::InvalidString => write!(f, "message carries invalid UTF-8"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    // A str longer than its length field can say.
+    TooLong(usize),
+    // More entries than their count field can say.
+    TooMany(usize),
+    // A whole message bigger than any peer reads at once.
+    TooBig(usize),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncodeError::TooLong(len) => write!(f, "a string of {} bytes is too long to send", len),
+            EncodeError::TooMany(count) => write!(f, "{} entries are too many to send", count),
+            EncodeError::TooBig(size) => write!(
+                f,
+                "a message of {} bytes doesn't fit in a datagram of {}",
+                size, BUF_SIZE
+            ),
+        }
+    }
+}
+
+impl From<EncodeError> for io::Error {
+    fn from(e: EncodeError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+    }
+}
+
+impl Message {
+    pub fn encode(&self) -> Result<Vec<u8>, EncodeError> {
+        self.encode_at(Instant::now())
+    }
+
+    // Peer ages are taken at `now`, which is some other clock's now in the simulator.
+    pub fn encode_at(&self, now: Instant) -> Result<Vec<u8>, EncodeError> {
+        let mut buf = vec![PROTOCOL_VERSION];
+        match self {
+            Message::Discovery { sender_id, nodes } => {
+                buf.push(DISCOVERY);
+                put_u128(&mut buf, *sender_id);
+                put_count(&mut buf, nodes.len())?;
+                for node in nodes {
+                    put_str(&mut buf, &node.name)?;
+                    buf.extend_from_slice(&node.ip.octets());
+                    put_u16(&mut buf, node.port);
+                    let age = node.age_at(now).as_millis().min(u32::MAX as u128) as u32;
+                    put_u32(&mut buf, age);
+                    put_u128(&mut buf, node.id);
+                }
+            }
+            Message::Get {
+                query_id,
+                ttl,
+                file_name,
+            } => {
+                buf.push(GET);
+                put_u128(&mut buf, *query_id);
+                buf.push(*ttl);
+                put_str(&mut buf, file_name)?;
+            }
+            Message::GetAck {
+                query_id,
+                responder,
+                data_port,
+                file_size,
+                digest,
+                merkle_root,
+                file_name,
+            } => {
+                buf.push(GET_ACK);
+                put_u128(&mut buf, *query_id);
+                put_socket_addr(&mut buf, responder);
+                put_u16(&mut buf, *data_port);
+                put_u64(&mut buf, *file_size);
+                buf.extend_from_slice(digest);
+                buf.extend_from_slice(merkle_root);
+                put_str(&mut buf, file_name)?;
+            }
+            Message::TcpGet {
+                get_port,
+                offset,
+                length,
+                file_name,
+            } => {
+                buf.push(TCP_GET);
+                put_u16(&mut buf, *get_port);
+                put_u64(&mut buf, *offset);
+                put_opt_u64(&mut buf, *length);
+                put_str(&mut buf, file_name)?;
+            }
+            Message::RdtGet {
+                get_port,
+                session,
+                offset,
+                length,
+                file_name,
+            } => {
+                buf.push(RDT_GET);
+                put_u16(&mut buf, *get_port);
+                put_u32(&mut buf, *session);
+                put_u64(&mut buf, *offset);
+                put_opt_u64(&mut buf, *length);
+                put_str(&mut buf, file_name)?;
+            }
+            Message::GetPieces { first, file_name } => {
+                buf.push(GET_PIECES);
+                put_u32(&mut buf, *first);
+                put_str(&mut buf, file_name)?;
+            }
+            Message::Pieces {
+                first,
+                hashes,
+                file_name,
+            } => {
+                buf.push(PIECES);
+                put_u32(&mut buf, *first);
+                put_count(&mut buf, hashes.len())?;
+                for hash in hashes {
+                    buf.extend_from_slice(hash);
+                }
+                put_str(&mut buf, file_name)?;
+            }
+            Message::Search {
+                query_id,
+                ttl,
+                pattern,
+            } => {
+                buf.push(SEARCH);
+                put_u128(&mut buf, *query_id);
+                buf.push(*ttl);
+                put_str(&mut buf, pattern)?;
+            }
+            Message::SearchResults {
+                query_id,
+                responder,
+                pattern,
+                hits,
+            } => {
+                buf.push(SEARCH_RESULTS);
+                put_u128(&mut buf, *query_id);
+                put_socket_addr(&mut buf, responder);
+                put_str(&mut buf, pattern)?;
+                put_count(&mut buf, hits.len())?;
+                for hit in hits {
+                    put_str(&mut buf, &hit.file_name)?;
+                    put_u64(&mut buf, hit.size);
+                    buf.extend_from_slice(&hit.digest);
+                }
+            }
+        }
+        if buf.len() > BUF_SIZE {
+            return Err(EncodeError::TooBig(buf.len()));
+        }
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Message, DecodeError> {
+        Message::decode_at(buf, Instant::now())
+    }
+
+    pub fn decode_at(buf: &[u8], now: Instant) -> Result<Message, DecodeError> {
+        let mut reader = MessageReader { buf, pos: 0 };
+        let version = reader.u8()?;
+        if version != PROTOCOL_VERSION {
+            return Err(DecodeError::Version(version));
+        }
+        let message = match reader.u8()? {
+            DISCOVERY => {
+                let sender_id = reader.u128()?;
+                let count = reader.u16()?;
+                let mut nodes = Vec::new();
+                for _ in 0..count {
+                    let name = reader.str()?;
+                    let ip = reader.ipv4()?;
+                    let port = reader.u16()?;
+                    let age = Duration::from_millis(reader.u32()? as u64);
+                    nodes.push(Node {
+                        name,
+                        ip,
+                        port,
+                        last_seen: now.checked_sub(age).unwrap_or(now),
+                        id: reader.u128()?,
+                        ..Default::default()
+                    });
+                }
+                Message::Discovery { sender_id, nodes }
+            }
+            GET => Message::Get {
+                query_id: reader.u128()?,
+                ttl: reader.u8()?,
+                file_name: reader.str()?,
+            },
+            GET_ACK => Message::GetAck {
+                query_id: reader.u128()?,
+                responder: reader.socket_addr()?,
+                data_port: reader.u16()?,
+                file_size: reader.u64()?,
+                digest: reader.digest()?,
+                merkle_root: reader.digest()?,
+                file_name: reader.str()?,
+            },
+            TCP_GET => Message::TcpGet {
+                get_port: reader.u16()?,
+                offset: reader.u64()?,
+                length: reader.opt_u64()?,
+                file_name: reader.str()?,
+            },
+            RDT_GET => Message::RdtGet {
+                get_port: reader.u16()?,
+                session: reader.u32()?,
+                offset: reader.u64()?,
+                length: reader.opt_u64()?,
+                file_name: reader.str()?,
+            },
+            GET_PIECES => Message::GetPieces {
+                first: reader.u32()?,
+                file_name: reader.str()?,
+            },
+            PIECES => {
+                let first = reader.u32()?;
+                let count = reader.u16()?;
+                let mut hashes = Vec::new();
+                for _ in 0..count {
+                    hashes.push(reader.digest()?);
+                }
+                Message::Pieces {
+                    first,
+                    hashes,
+                    file_name: reader.str()?,
+                }
+            }
+            SEARCH => Message::Search {
+                query_id: reader.u128()?,
+                ttl: reader.u8()?,
+                pattern: reader.str()?,
+            },
+            SEARCH_RESULTS => {
+                let query_id = reader.u128()?;
+                let responder = reader.socket_addr()?;
+                let pattern = reader.str()?;
+                let count = reader.u16()?;
+                let mut hits = Vec::new();
+                for _ in 0..count {
+                    hits.push(SearchHit {
+                        file_name: reader.str()?,
+                        size: reader.u64()?,
+                        digest: reader.digest()?,
+                    });
+                }
+                Message::SearchResults {
+                    query_id,
+                    responder,
+                    pattern,
+                    hits,
+                }
+            }
+            kind => return Err(DecodeError::UnknownKind(kind)),
+        };
+        Ok(message)
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, value: u32) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_u64(buf: &mut Vec<u8>, value: u64) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_opt_u64(buf: &mut Vec<u8>, value: Option<u64>) {
+    match value {
+        Some(value) => {
+            buf.push(1);
+            put_u64(buf, value);
+        }
+        None => buf.push(0),
+    }
+}
+
+fn put_u128(buf: &mut Vec<u8>, value: u128) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put_socket_addr(buf: &mut Vec<u8>, addr: &SocketAddrV4) {
+    buf.extend_from_slice(&addr.ip().octets());
+    put_u16(buf, addr.port());
+}
+
+fn put_count(buf: &mut Vec<u8>, count: usize) -> Result<(), EncodeError> {
+    let count = u16::try_from(count).map_err(|_| EncodeError::TooMany(count))?;
+    put_u16(buf, count);
+    Ok(())
+}
+
+fn put_str(buf: &mut Vec<u8>, value: &str) -> Result<(), EncodeError> {
+    let len = u16::try_from(value.len()).map_err(|_| EncodeError::TooLong(value.len()))?;
+    put_u16(buf, len);
+    buf.extend_from_slice(value.as_bytes());
+    Ok(())
+}
+
+struct MessageReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> MessageReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(DecodeError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], DecodeError> {
+        self.take(N)?.try_into().map_err(|_| DecodeError::Truncated)
+    }
+
+    fn u8(&mut self) -> Result<u8, DecodeError> {
+        Ok(u8::from_be_bytes(self.array()?))
+    }
+
+    fn u16(&mut self) -> Result<u16, DecodeError> {
+        Ok(u16::from_be_bytes(self.array()?))
+    }
+
+    fn u32(&mut self) -> Result<u32, DecodeError> {
+        Ok(u32::from_be_bytes(self.array()?))
+    }
+
+    fn u64(&mut self) -> Result<u64, DecodeError> {
+        Ok(u64::from_be_bytes(self.array()?))
+    }
+
+    fn opt_u64(&mut self) -> Result<Option<u64>, DecodeError> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => Ok(Some(self.u64()?)),
+        }
+    }
+
+    fn u128(&mut self) -> Result<u128, DecodeError> {
+        Ok(u128::from_be_bytes(self.array()?))
+    }
+
+    fn digest(&mut self) -> Result<FileDigest, DecodeError> {
+        self.array()
+    }
+
+    fn ipv4(&mut self) -> Result<Ipv4Addr, DecodeError> {
+        Ok(Ipv4Addr::from(self.array::<4>()?))
+    }
+
+    fn socket_addr(&mut self) -> Result<SocketAddrV4, DecodeError> {
+        Ok(SocketAddrV4::new(self.ipv4()?, self.u16()?))
+    }
+
+    fn str(&mut self) -> Result<String, DecodeError> {
+        let len = self.u16()? as usize;
+        let bytes = self.take(len)?;
+        std::str::from_utf8(bytes)
+            .map(String::from)
+            .map_err(|_| DecodeError::InvalidString)
+    }
+}
@@ -1,167 +1,464 @@
+use crate::context::NodeContext;
+use crate::dir::{index, merkle, FileDigest};
+use crate::download::{self, DownloadEvent, RangeFetcher};
+use crate::error;
 use crate::networking::{
-    self, bind_udp_socket, ip_port_string, node_of_packet, BUF_SIZE, CURRENT_DATA_CLIENTS,
-    DISCOVERY_INTERVAL_MS, MAX_DATA_CLIENTS, UDP_GET_PORT,
+    bind_udp_socket, ip_port_string, mark_alive, node_of_packet, spawn_until, BUF_SIZE,
+    DEFAULT_QUERY_TTL, DISCOVERY_INTERVAL_MS, MAX_DATA_CLIENTS, MAX_PATTERN_LEN, MAX_PATTERN_STARS,
+    MAX_PIECES, PIECE_HASHES_PER_MESSAGE, RDT_MAX_RETRIES,
 };
+use crate::search::{self, SearchEvent};
 use crate::tcp::tcp_server;
-use crate::DATA_CONN_TYPE;
 use crate::{dir, node, tcp};
-use log::info;
+use log::{info, warn};
+use message::Message;
 use std::collections::HashSet;
 use std::io::{Error, ErrorKind};
-use std::net::{SocketAddr, UdpSocket};
-use std::sync::{mpsc, mpsc::Receiver, Arc, RwLock};
-use std::{thread, time};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, RwLock};
+use std::time;
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
 pub mod headers;
+pub mod link;
+pub mod message;
+pub mod query;
 mod reliable;
+pub mod rtt;
 
-fn send_bytes_to_udp_socket(
-    data: &[u8],
+async fn send_message_to_udp_socket(
+    message: &Message,
     node: &node::Node,
     socket: &UdpSocket,
 ) -> Result<usize, Error> {
-    let target_addr = ip_port_string(node.ip, node.port);
-    // Don't really care if it fails.
-    socket.send_to(data, target_addr)
+    send_message_to(message, ip_port_string(node.ip, node.port), socket).await
 }
 
-fn receive_string_from_udp_socket(socket: &UdpSocket) -> Result<(String, SocketAddr), Error> {
+// Don't really care if it fails, but a message we can't even encode is worth a warning.
+async fn send_message_to<A: ToSocketAddrs>(
+    message: &Message,
+    target: A,
+    socket: &UdpSocket,
+) -> Result<usize, Error> {
+    let data = message.encode().map_err(|e| {
+        warn!("Not sending a message: {}", e);
+        e
+    })?;
+    socket.send_to(&data, target).await
+}
+
+async fn receive_message_from_udp_socket(
+    socket: &UdpSocket,
+) -> error::Result<(Message, SocketAddr)> {
     let mut buf = [0; BUF_SIZE];
-    //This is just a ridiculous trick to get over all of rust's size-checking.
-    let err = Error::new(ErrorKind::Other, "OH NONONO");
-    let (amt, src) = match socket.recv_from(&mut buf) {
-        Ok((amt, src)) => (amt, src),
-        Err(e) => return Err(e),
-    };
+    let (amt, src) = socket.recv_from(&mut buf).await?;
     //This is where the data is fully received
-    match std::str::from_utf8(&buf[..amt]) {
-        Ok(string) => Ok((string.to_string(), src)),
-        Err(_) => Err(err),
+    match Message::decode(&buf[..amt]) {
+        Ok(message) => Ok((message, src)),
+        Err(e) => {
+            warn!("Rejected packet from {}: {}", src, e);
+            Err(e.into())
+        }
     }
 }
 
-pub fn discovery_server(
-    receiver: Receiver<String>,
-    socket: UdpSocket,
+pub async fn discovery_server(
+    ctx: Arc<NodeContext>,
+    mut receiver: UnboundedReceiver<(node::Node, Vec<node::Node>)>,
+    socket: Arc<UdpSocket>,
     nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
 ) {
     let discovery_interval = time::Duration::from_millis(DISCOVERY_INTERVAL_MS);
     let nodes_rwlock = nodes_arc.clone();
     let local_address = socket.local_addr().unwrap().to_string();
     loop {
-        let mut received_nodes: HashSet<node::Node> = HashSet::new();
+        let mut heard: Vec<(node::Node, Vec<node::Node>)> = Vec::new();
         // Read until there are no more incoming disccovery packets.
         // This should not wait for data and do its job indefinitely.
-        loop {
-            let data = match receiver.try_recv() {
-                Ok(data) => data,
-                Err(_) => break,
-            };
-            let mut new_nodes = node::Node::multiple_from_string(data, true);
-            new_nodes.retain(|k| ip_port_string(k.ip, k.port) != local_address);
-            received_nodes.extend(new_nodes);
+        while let Ok(pair) = receiver.try_recv() {
+            heard.push(pair);
         }
-        let mut nodes_ptr = nodes_rwlock.write().unwrap();
-        nodes_ptr.extend(received_nodes);
-        drop(nodes_ptr);
-        let nodes_ptr = nodes_rwlock.read().unwrap();
-        let nodes = &*nodes_ptr;
-        let node_strings = node::Node::nodes_to_string(nodes);
-        for node in nodes {
-            let _ = match send_bytes_to_udp_socket(node_strings.as_bytes(), node, &socket) {
-                Ok(__) => __,
-                Err(_) => continue,
-            };
+        // No lock is held across a send, the other tasks need the list too.
+        let nodes: Vec<node::Node> = {
+            let mut nodes_ptr = nodes_rwlock.write().unwrap();
+            absorb_discovery(
+                &mut nodes_ptr,
+                heard,
+                &local_address,
+                ctx.id,
+                ctx.config.peer_expiry,
+                time::Instant::now(),
+            );
+            nodes_ptr.iter().cloned().collect()
+        };
+        let discoveries = discovery_messages(ctx.id, &nodes);
+        for node in &nodes {
+            for discovery in &discoveries {
+                let _ = send_message_to_udp_socket(discovery, node, &socket).await;
+            }
         }
-        drop(nodes_ptr);
-        thread::sleep(discovery_interval);
+        tokio::time::sleep(discovery_interval).await;
     }
 }
 
-pub fn get_server(
-    receiver: Receiver<(String, SocketAddr)>,
-    socket: UdpSocket,
+// A peer's name length, ip, port, age and id, before the name itself.
+const MIN_PEER_SIZE: usize = 2 + 4 + 2 + 4 + 16;
+
+// Splits our list of peers into as many discoveries as it takes for each one to fit in a datagram.
+// There's always at least one, even an empty discovery tells its receiver we're alive.
+pub fn discovery_messages(sender_id: u128, nodes: &[node::Node]) -> Vec<Message> {
+    // Leave room for the header, our id and the peer count.
+    let budget = BUF_SIZE - 20;
+    let discovery = |nodes| Message::Discovery { sender_id, nodes };
+    let mut messages = Vec::new();
+    let mut chunk: Vec<node::Node> = Vec::new();
+    let mut chunk_size = 0;
+    for node in nodes {
+        let node_size = MIN_PEER_SIZE + node.name.len();
+        if node_size > budget {
+            info!("{} doesn't fit in a discovery", node);
+            continue;
+        }
+        if !chunk.is_empty() && chunk_size + node_size > budget {
+            messages.push(discovery(std::mem::take(&mut chunk)));
+            chunk_size = 0;
+        }
+        chunk_size += node_size;
+        chunk.push(node.clone());
+    }
+    if !chunk.is_empty() || messages.is_empty() {
+        messages.push(discovery(chunk));
+    }
+    messages
+}
+
+// Folds the senders of the discoveries heard since the last round, and the nodes they told us
+// about, into our own list, then forgets whoever has been silent for longer than `expiry`.
+pub fn absorb_discovery(
+    nodes: &mut HashSet<node::Node>,
+    heard: Vec<(node::Node, Vec<node::Node>)>,
+    local_address: &str,
+    own_id: u128,
+    expiry: time::Duration,
+    now: time::Instant,
+) {
+    let mut senders: Vec<node::Node> = Vec::new();
+    let mut received_nodes: Vec<node::Node> = Vec::new();
+    for (sender, mut new_nodes) in heard {
+        new_nodes.retain(|k| ip_port_string(k.ip, k.port) != local_address && k.id != own_id);
+        senders.push(sender);
+        received_nodes.extend(new_nodes);
+    }
+    // Senders tell us their id, which is how a known peer on a new port is recognized.
+    for sender in senders {
+        let is_known = nodes
+            .iter()
+            .any(|node| node == &sender || (sender.id != node::UNKNOWN_ID && node.id == sender.id));
+        if is_known {
+            node::merge_node(nodes, sender);
+        }
+    }
+    for received in received_nodes {
+        node::merge_node(nodes, received);
+    }
+    nodes.retain(|node| {
+        let alive = node.age_at(now) < expiry;
+        if !alive {
+            info!(
+                "Forgetting {}, not heard from in {:?}",
+                node,
+                node.age_at(now)
+            );
+        }
+        alive
+    });
+}
+
+pub async fn get_server(
+    ctx: Arc<NodeContext>,
+    mut receiver: UnboundedReceiver<(Message, SocketAddr)>,
+    socket: Arc<UdpSocket>,
     nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+    downloads: UnboundedSender<DownloadEvent>,
+    searches: UnboundedSender<SearchEvent>,
 ) {
-    loop {
-        let data_pair: (String, SocketAddr) = match receiver.recv() {
-            Ok(data) => data,
-            Err(_) => break,
-        };
+    while let Some(data_pair) = receiver.recv().await {
         // If the node is unknown, insert it into our currently known nodes.
         let data = &data_pair.0;
-        let addr = &(&data_pair.1).to_string();
-        info!("Received {} from {}", data, addr);
-        let (current_node, _) = node_of_packet(nodes_arc.clone(), addr);
+        let addr = &data_pair.1.to_string();
+        info!("Received {:?} from {}", data, addr);
+        let current_node = match node_of_packet(nodes_arc.clone(), addr) {
+            Ok((node, _)) => node,
+            Err(e) => {
+                warn!("Ignoring a packet from {}: {}", addr, e);
+                continue;
+            }
+        };
         info!("Recognized node's packet.");
-        let mut data_lines = data.lines();
-        // Send ACK to GET request
-        if data_lines
-            .next()
-            .unwrap()
-            .starts_with(headers::PacketHeader::get().trim())
-        {
-            // Becomes useless, so why should it keep the rwlock?
-            let file_name = &data_lines.next().unwrap();
-            let client_count = *CURRENT_DATA_CLIENTS.read().unwrap();
-            info!("All is fine this far.");
-            // Don't respond if you don't have the file ot the TCP Server is swamped with too many clients.
-            // For the reason why "contains" is not used, please refer to:
-            // https://github.com/rust-lang/rust/issues/42671
-            if dir::file_list().iter().any(|x| x == file_name) && MAX_DATA_CLIENTS > client_count {
-                info!("Recognizing the existence of the requested file.");
-                let mut response = String::from(headers::PacketHeader::ack());
-                response.push_str(&networking::DATA_SENDER_PORT.to_string());
-                response.push('\n');
-                // Because the node might not remember what it requested! :))
-                response.push_str(&file_name);
-                info!("The proper response is: {}", response);
-                let _ = match send_bytes_to_udp_socket(
-                    response.to_string().as_bytes(),
-                    &current_node,
-                    &socket,
-                ) {
-                    Ok(__) => (),
-                    Err(_) => continue,
+        match data {
+            // Send ACK to GET request
+            Message::Get {
+                query_id,
+                file_name,
+                ..
+            } => {
+                if !query::first_sighting(&ctx, *query_id, data_pair.1) {
+                    info!("Dropping a GET that already reached us");
+                    continue;
+                }
+                if let Some(forwarded) = query::next_hop(data) {
+                    flood(&forwarded, data_pair.1, &nodes_arc, &socket).await;
+                }
+                let client_count = *ctx.data_clients.read().unwrap();
+                info!("All is fine this far.");
+                // Don't respond if you don't have the file ot the TCP Server is swamped with too many clients.
+                // For the reason why "contains" is not used, please refer to:
+                // https://github.com/rust-lang/rust/issues/42671
+                let hashes = shared_file_hashes(&ctx, file_name).await;
+                if let (Some(hashes), true) = (hashes, MAX_DATA_CLIENTS > client_count) {
+                    info!("Recognizing the existence of the requested file.");
+                    let response = get_ack(*query_id, ctx.data_port(), &hashes, file_name);
+                    info!("The proper response is: {:?}", response);
+                    if send_message_to_udp_socket(&response, &current_node, &socket)
+                        .await
+                        .is_err()
+                    {
+                        continue;
+                    }
+                    info!("No problem sending GET/ACK over UDP Socket.");
+                } else {
+                    info!("File not found, denying the GET request");
+                }
+            }
+            // A node has ACK'd one of your previous requests, it joins that file's swarm.
+            // ACKs to requests of others are passed back the way the request came.
+            Message::GetAck {
+                query_id,
+                responder,
+                data_port,
+                file_size,
+                digest,
+                merkle_root,
+                file_name,
+            } => {
+                let responder = responder_addr(responder, data_pair.1);
+                match query::route_of(&ctx, *query_id) {
+                    Some(query::Route::Origin) => {
+                        let mut data_socket_addr = SocketAddr::V4(responder);
+                        data_socket_addr.set_port(*data_port);
+                        let prior_comms = node_of_packet(nodes_arc.clone(), &responder.to_string())
+                            .map(|(node, _)| node.prior_communications)
+                            .unwrap_or(0);
+                        let _ = downloads.send(DownloadEvent::Offered {
+                            file_name: file_name.clone(),
+                            control_addr: SocketAddr::V4(responder),
+                            data_addr: data_socket_addr,
+                            file_size: *file_size,
+                            digest: *digest,
+                            merkle_root: *merkle_root,
+                            prior_comms,
+                        });
+                    }
+                    Some(query::Route::Upstream(upstream)) => {
+                        if let Some(forwarded) = query::relayed(data, responder) {
+                            let _ = send_message_to(&forwarded, upstream, &socket).await;
+                        }
+                    }
+                    None => info!("Dropping a GET ACK to a query we don't remember"),
+                }
+            }
+            // Hand out the next chunk of a file's piece hashes, an empty chunk means there are no more.
+            Message::GetPieces { first, file_name } => {
+                let hashes = match shared_file_hashes(&ctx, file_name).await {
+                    Some(hashes) => hashes,
+                    None => continue,
                 };
-                info!("No problem sending GET/ACK over UDP Socket.");
-            } else {
-                info!("File not found, denying the GET request");
+                let first_index = (*first as usize).min(hashes.pieces.len());
+                let last_index = (first_index + PIECE_HASHES_PER_MESSAGE).min(hashes.pieces.len());
+                let response = Message::Pieces {
+                    first: *first,
+                    hashes: hashes.pieces[first_index..last_index].to_vec(),
+                    file_name: file_name.clone(),
+                };
+                let _ = send_message_to_udp_socket(&response, &current_node, &socket).await;
             }
-        }
-        // Connect to a node that has ACK'd one of your previous requests.
-        else {
-            let mut data_socket_addr = data_pair.1.clone();
-            let port_str = data_lines.next().unwrap();
-            data_socket_addr.set_port(port_str.parse::<u16>().unwrap());
-            let file_name = data_lines.next().unwrap().to_string();
-            match *DATA_CONN_TYPE.read().unwrap() {
-                headers::ConnectionType::TCP => {
-                    thread::spawn(move || tcp::tcp_client(data_socket_addr, file_name));
+            // Only answer when something matches, like with GETs.
+            Message::Search {
+                query_id, pattern, ..
+            } => {
+                if !search::valid_pattern(pattern) {
+                    info!("Dropping a SEARCH with an unreasonable pattern");
+                    continue;
                 }
-                headers::ConnectionType::SAndW => {
-                    thread::spawn(move || reliable::sw_client(data_socket_addr, file_name));
+                if !query::first_sighting(&ctx, *query_id, data_pair.1) {
+                    info!("Dropping a SEARCH that already reached us");
+                    continue;
                 }
-                headers::ConnectionType::GoBackN => {
-                    thread::spawn(move || tcp::tcp_client(data_socket_addr, file_name));
+                if let Some(forwarded) = query::next_hop(data) {
+                    flood(&forwarded, data_pair.1, &nodes_arc, &socket).await;
                 }
-                headers::ConnectionType::SRepeat => {
-                    thread::spawn(move || tcp::tcp_client(data_socket_addr, file_name));
+                let hits = search::local_hits_async(&ctx, pattern).await;
+                info!("{} shared files match {}", hits.len(), pattern);
+                for response in search::result_messages(*query_id, pattern, hits) {
+                    let _ = send_message_to_udp_socket(&response, &current_node, &socket).await;
                 }
-            };
+            }
+            Message::SearchResults {
+                query_id,
+                responder,
+                pattern,
+                hits,
+            } => {
+                let responder = responder_addr(responder, data_pair.1);
+                match query::route_of(&ctx, *query_id) {
+                    Some(query::Route::Origin) => {
+                        let _ = searches.send(SearchEvent::Found {
+                            pattern: pattern.clone(),
+                            peer: SocketAddr::V4(responder),
+                            hits: hits.clone(),
+                        });
+                    }
+                    Some(query::Route::Upstream(upstream)) => {
+                        if let Some(forwarded) = query::relayed(data, responder) {
+                            let _ = send_message_to(&forwarded, upstream, &socket).await;
+                        }
+                    }
+                    None => info!("Dropping search results to a query we don't remember"),
+                }
+            }
+            _ => info!("Packet was not recognized!"),
         }
     }
 }
 
-pub fn get_client(
-    receiver: Receiver<String>,
-    socket: UdpSocket,
-    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+// Passes a query on to every peer but the one it came from.
+async fn flood(
+    request: &Message,
+    sender: SocketAddr,
+    nodes_arc: &Arc<RwLock<HashSet<node::Node>>>,
+    socket: &UdpSocket,
 ) {
-    loop {
-        let input = match receiver.recv() {
-            Ok(data) => data,
-            Err(_) => continue,
+    let sender = sender.to_string();
+    let nodes: Vec<node::Node> = nodes_arc.read().unwrap().iter().cloned().collect();
+    for node in nodes {
+        if !node.has_same_address(&sender) {
+            info!("Forwarding {:?} to {}", request, node);
+            send_message_to_udp_socket(request, &node, socket)
+                .await
+                .unwrap_or(0);
+        }
+    }
+}
+
+// Answers leave their responder unspecified when it's the node that sent them.
+pub fn responder_addr(responder: &SocketAddrV4, sender: SocketAddr) -> SocketAddrV4 {
+    match sender {
+        SocketAddr::V4(sender) if responder.ip().is_unspecified() => sender,
+        _ => *responder,
+    }
+}
+
+// Our answer to a GET for a file we share.
+pub fn get_ack(
+    query_id: u128,
+    data_port: u16,
+    hashes: &merkle::FileHashes,
+    file_name: &str,
+) -> Message {
+    Message::GetAck {
+        query_id,
+        // The first hop knows our address better than we do.
+        responder: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+        data_port,
+        file_size: hashes.size,
+        digest: hashes.digest,
+        merkle_root: hashes.merkle_root(),
+        // Because the node might not remember what it requested! :))
+        file_name: file_name.to_string(),
+    }
+}
+
+async fn shared_file_hashes(ctx: &Arc<NodeContext>, file_name: &str) -> Option<merkle::FileHashes> {
+    index::lookup_async(ctx, file_name)
+        .await
+        .map(|entry| entry.hashes)
+}
+
+// Asks a peer for all the piece hashes of a file, a chunk at a time, over a socket of our own.
+pub async fn fetch_piece_hashes(
+    ctx: &NodeContext,
+    control_addr: SocketAddr,
+    file_name: &str,
+    piece_count: usize,
+) -> std::io::Result<Vec<FileDigest>> {
+    if piece_count as u64 > MAX_PIECES {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "peer claims more pieces than any file we download",
+        ));
+    }
+    let socket = bind_udp_socket(ctx.config.ip, ctx.config.data_receiver_port).await;
+    socket.connect(control_addr).await?;
+    let mut rtt = rtt::RttEstimator::new();
+    // Grows only as hashes actually arrive.
+    let mut hashes: Vec<FileDigest> = Vec::new();
+    let mut retries = 0;
+    let mut buf = [0; BUF_SIZE];
+    while hashes.len() < piece_count {
+        let request = Message::GetPieces {
+            first: hashes.len() as u32,
+            file_name: file_name.to_string(),
         };
+        socket.send(&request.encode()?).await?;
+        let sent_at = time::Instant::now();
+        let size = match tokio::time::timeout(rtt.rto(), socket.recv(&mut buf)).await {
+            Ok(Ok(size)) => size,
+            _ => {
+                rtt.back_off();
+                retries += 1;
+                if retries > RDT_MAX_RETRIES {
+                    return Err(Error::new(
+                        ErrorKind::TimedOut,
+                        "peer stopped sending piece hashes",
+                    ));
+                }
+                continue;
+            }
+        };
+        // Late answers to an earlier request are told apart by their first piece.
+        if let Ok(Message::Pieces {
+            first,
+            hashes: chunk,
+            ..
+        }) = Message::decode(&buf[..size])
+        {
+            if first as usize == hashes.len() {
+                // An answer to a resent request might be the one to the first copy.
+                if retries == 0 {
+                    rtt.sample(sent_at.elapsed());
+                }
+                retries = 0;
+                if chunk.is_empty() {
+                    return Err(Error::other("peer has fewer pieces than it claimed"));
+                }
+                hashes.extend(chunk);
+            }
+        }
+    }
+    hashes.truncate(piece_count);
+    Ok(hashes)
+}
+
+pub async fn get_client(
+    ctx: Arc<NodeContext>,
+    mut receiver: UnboundedReceiver<String>,
+    socket: Arc<UdpSocket>,
+    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+    downloads: UnboundedSender<DownloadEvent>,
+    searches: UnboundedSender<SearchEvent>,
+) {
+    while let Some(input) = receiver.recv().await {
         info!("Received data");
         let mut commands = input.split(" ");
         let arg = commands.next().unwrap();
@@ -169,6 +466,41 @@ pub fn get_client(
         if arg.starts_with(headers::StdinHeader::list()) {
             let value = &*nodes_arc.read().unwrap();
             info!("{:?}", value);
+            for node in value {
+                println!(
+                    "{} [{}] (last seen {:.1}s ago)",
+                    node,
+                    node.short_id(),
+                    node.age().as_secs_f32()
+                );
+            }
+        } else if arg.starts_with(headers::StdinHeader::search()) {
+            // Patterns may have spaces in them, so it's the rest of the line.
+            let pattern = commands.collect::<Vec<&str>>().join(" ");
+            let pattern = pattern.trim();
+            if pattern.is_empty() {
+                continue;
+            }
+            if !search::valid_pattern(pattern) {
+                println!(
+                    "Search patterns are limited to {} bytes and {} stars",
+                    MAX_PATTERN_LEN, MAX_PATTERN_STARS
+                );
+                continue;
+            }
+            let _ = searches.send(SearchEvent::Started(pattern.to_string()));
+            let request = Message::Search {
+                query_id: query::new_query(&ctx),
+                ttl: DEFAULT_QUERY_TTL,
+                pattern: pattern.to_string(),
+            };
+            info!("Preparing to broadcast SEARCH");
+            let nodes: Vec<node::Node> = nodes_arc.read().unwrap().iter().cloned().collect();
+            for node in &nodes {
+                send_message_to_udp_socket(&request, node, &socket)
+                    .await
+                    .unwrap_or(0);
+            }
         } else if arg.starts_with(headers::StdinHeader::get()) {
             info!("Understand GET");
             // Make sure there is a file name!
@@ -176,80 +508,214 @@ pub fn get_client(
                 Some(cmd) => cmd.trim(),
                 None => continue,
             };
-            let nodes_ptr = nodes_arc.read().unwrap();
-            let nodes = &*nodes_ptr;
+            // `get #3` picks the third result of the last search, and only that content will do.
+            let (file_name, digest) = match file_name.strip_prefix('#') {
+                Some(number) => match number
+                    .parse()
+                    .ok()
+                    .and_then(|number| search::numbered_hit(&ctx, number))
+                {
+                    Some(hit) => (hit.file_name, Some(hit.digest)),
+                    None => {
+                        println!("There is no search result {}", file_name);
+                        continue;
+                    }
+                },
+                None => (file_name.to_string(), None),
+            };
+            // It's also where the download goes, so it has to stay inside our shared directory.
+            let file_name = match dir::normalize_shared_path(&file_name) {
+                Some(file_name) => file_name,
+                None => {
+                    println!("{} is not a valid relative path", file_name);
+                    continue;
+                }
+            };
+            // Every copy carries the same id, so peers reached over several paths answer once.
+            let request = Message::Get {
+                query_id: query::new_query(&ctx),
+                ttl: DEFAULT_QUERY_TTL,
+                file_name: file_name.to_string(),
+            };
+            if let Err(e) = request.encode() {
+                println!("Can't ask for {}: {}", file_name, e);
+                continue;
+            }
+            let _ = downloads.send(DownloadEvent::Requested {
+                file_name: file_name.clone(),
+                digest,
+            });
+            let nodes: Vec<node::Node> = nodes_arc.read().unwrap().iter().cloned().collect();
             info!("Preparing to broadcast GET");
-            for node in nodes {
+            info!("The request is: {:?}", request);
+            for node in &nodes {
                 info!("GET sent to {}", node);
-                let mut request = String::from(headers::PacketHeader::get());
-                request.push_str(file_name);
-                info!("The request is: {}", request);
                 let target_addr = ip_port_string(node.ip, node.port);
                 info!("{}", target_addr);
-                send_bytes_to_udp_socket(request.as_bytes(), node, &socket).unwrap_or(0);
+                send_message_to_udp_socket(&request, node, &socket)
+                    .await
+                    .unwrap_or(0);
             }
         }
     }
 }
 
-pub fn main_server(init_nodes_dir: String, stdin_rx: Receiver<String>) {
+// How this node fetches a piece of a file, depending on the data connection type.
+fn range_fetcher(conn_type: &headers::ConnectionType) -> RangeFetcher {
+    match conn_type {
+        headers::ConnectionType::TCP => |ctx, addr, file_name, offset, length| {
+            Box::pin(tcp::tcp_client(ctx, addr, file_name, offset, length))
+        },
+        headers::ConnectionType::SAndW => |ctx, addr, file_name, offset, length| {
+            Box::pin(reliable::sw_client(ctx, addr, file_name, offset, length))
+        },
+        headers::ConnectionType::GoBackN => |ctx, addr, file_name, offset, length| {
+            Box::pin(reliable::gbn_client(ctx, addr, file_name, offset, length))
+        },
+        headers::ConnectionType::SRepeat => |ctx, addr, file_name, offset, length| {
+            Box::pin(reliable::sr_client(ctx, addr, file_name, offset, length))
+        },
+    }
+}
+
+// Reads the starting nodes, binds the control socket and runs the node on them.
+pub async fn main_server(
+    ctx: Arc<NodeContext>,
+    init_nodes_dir: String,
+    stdin_rx: UnboundedReceiver<String>,
+    shutdown: CancellationToken,
+) {
     // The fact whether or not this actually gets updated is still a question. :)))
-    let nodes = node::read_starting_nodes(&init_nodes_dir);
-    let nodes_rwlock = RwLock::new(nodes);
-    let socket = bind_udp_socket(UDP_GET_PORT, true);
-    let nodes_arc = Arc::new(nodes_rwlock);
+    let nodes = match node::read_starting_nodes(&init_nodes_dir) {
+        Ok(nodes) => nodes,
+        Err(e) => {
+            warn!(
+                "Starting without any known nodes, can't read {}: {}",
+                init_nodes_dir, e
+            );
+            HashSet::new()
+        }
+    };
+    let socket = bind_control_socket(&ctx).await;
+    run_node(
+        ctx,
+        socket,
+        Arc::new(RwLock::new(nodes)),
+        stdin_rx,
+        shutdown,
+    )
+    .await
+}
+
+pub async fn bind_control_socket(ctx: &NodeContext) -> UdpSocket {
+    let socket = bind_udp_socket(ctx.config.ip, ctx.config.control_port).await;
+    // Peers know us by the port we really got, so that's the one our data GETs carry.
+    ctx.set_control_port(socket.local_addr().unwrap().port());
     info!(
         "Opened UDP socket on {:?}",
         socket.local_addr().unwrap().to_string()
     );
-    let (discovery_tx, discovery_rx) = mpsc::channel::<String>();
-    let (get_server_tx, get_server_rx) = mpsc::channel::<(String, SocketAddr)>();
-    //Spawn the clones first kids! Don't do it while calling the function. :)))))))
-    let socket_disc = socket.try_clone().unwrap();
-    let node_arc_disc_clone = nodes_arc.clone();
-    thread::spawn(|| discovery_server(discovery_rx, socket_disc, node_arc_disc_clone));
-    let socket_get_server = socket.try_clone().unwrap();
-    let nodes_arc_get_server = nodes_arc.clone();
-    thread::spawn(|| get_server(get_server_rx, socket_get_server, nodes_arc_get_server));
-    let socket_get_client = socket.try_clone().unwrap();
-    let nodes_arc_get_client = nodes_arc.clone();
-    std::thread::spawn(|| get_client(stdin_rx, socket_get_client, nodes_arc_get_client));
+    socket
+}
+
+// Every part of the node is a task of its own, and all of them stop once `shutdown` is cancelled.
+// Commands are the lines the user types, like `get a.txt` or `list`.
+pub async fn run_node(
+    ctx: Arc<NodeContext>,
+    socket: UdpSocket,
+    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+    stdin_rx: UnboundedReceiver<String>,
+    shutdown: CancellationToken,
+) {
+    let socket = Arc::new(socket);
+    let (discovery_tx, discovery_rx) = mpsc::unbounded_channel::<(node::Node, Vec<node::Node>)>();
+    let (get_server_tx, get_server_rx) = mpsc::unbounded_channel::<(Message, SocketAddr)>();
+    let (download_tx, download_rx) = mpsc::unbounded_channel::<DownloadEvent>();
+    let (search_tx, search_rx) = mpsc::unbounded_channel::<SearchEvent>();
+    spawn_until(
+        &shutdown,
+        discovery_server(ctx.clone(), discovery_rx, socket.clone(), nodes_arc.clone()),
+    );
+    let conn_type = ctx.config.conn_type;
+    let fetcher = range_fetcher(&conn_type);
+    spawn_until(
+        &shutdown,
+        download::download_manager(ctx.clone(), download_rx, fetcher, shutdown.clone()),
+    );
+    spawn_until(&shutdown, search::search_manager(ctx.clone(), search_rx));
+    spawn_until(
+        &shutdown,
+        get_server(
+            ctx.clone(),
+            get_server_rx,
+            socket.clone(),
+            nodes_arc.clone(),
+            download_tx.clone(),
+            search_tx.clone(),
+        ),
+    );
+    spawn_until(
+        &shutdown,
+        get_client(
+            ctx.clone(),
+            stdin_rx,
+            socket.clone(),
+            nodes_arc.clone(),
+            download_tx,
+            search_tx,
+        ),
+    );
     let nodes_arc_data_server = nodes_arc.clone();
-    match *DATA_CONN_TYPE.read().unwrap() {
-        headers::ConnectionType::TCP => {
-            thread::spawn(|| tcp_server(nodes_arc_data_server));
-        }
-        headers::ConnectionType::SAndW => {
-            thread::spawn(|| reliable::sw_server(nodes_arc_data_server));
-        }
-        headers::ConnectionType::GoBackN => {
-            thread::spawn(|| tcp_server(nodes_arc_data_server));
-        }
-        headers::ConnectionType::SRepeat => {
-            thread::spawn(|| tcp_server(nodes_arc_data_server));
+    let data_server_shutdown = shutdown.clone();
+    let data_server_ctx = ctx.clone();
+    let data_server = async move {
+        let (ctx, nodes_arc, shutdown) =
+            (data_server_ctx, nodes_arc_data_server, data_server_shutdown);
+        let result = match conn_type {
+            headers::ConnectionType::TCP => tcp_server(ctx, nodes_arc, shutdown).await,
+            headers::ConnectionType::SAndW => reliable::sw_server(ctx, nodes_arc, shutdown).await,
+            headers::ConnectionType::GoBackN => {
+                reliable::gbn_server(ctx, nodes_arc, shutdown).await
+            }
+            headers::ConnectionType::SRepeat => reliable::sr_server(ctx, nodes_arc, shutdown).await,
+        };
+        if let Err(e) = result {
+            warn!("The data server stopped: {}", e);
         }
     };
-    // Because https://github.com/rust-lang/rfcs/issues/372 is still in the works. :))
-    let mut data_addr_pair: (String, SocketAddr);
+    spawn_until(&shutdown, data_server);
     loop {
         // This function is the only one reading from the socket!
-        data_addr_pair = match receive_string_from_udp_socket(&socket) {
-            Ok((string, addr)) => (string, addr),
-            Err(_) => continue,
+        let data_addr_pair: (Message, SocketAddr) = tokio::select! {
+            _ = shutdown.cancelled() => return,
+            received = receive_message_from_udp_socket(&socket) => match received {
+                Ok((message, addr)) => (message, addr),
+                Err(_) => continue,
+            },
         };
-        let header = headers::PacketHeader::packet_type(&data_addr_pair.0);
-        if header == headers::PacketHeader::Disc {
-            match discovery_tx.send(data_addr_pair.0) {
-                Ok(_) => (),
-                Err(_) => (),
-            };
-        } else if header == headers::PacketHeader::GETACK || header == headers::PacketHeader::GET {
-            match get_server_tx.send(data_addr_pair) {
-                Ok(_) => (),
-                Err(_) => (),
+        let sender_ip = match data_addr_pair.1.ip() {
+            IpAddr::V4(v4) => v4,
+            IpAddr::V6(_) => continue,
+        };
+        mark_alive(nodes_arc.clone(), sender_ip, data_addr_pair.1.port());
+        match data_addr_pair.0 {
+            Message::Discovery { sender_id, nodes } => {
+                let sender = node::Node {
+                    ip: sender_ip,
+                    port: data_addr_pair.1.port(),
+                    id: sender_id,
+                    ..Default::default()
+                };
+                let _ = discovery_tx.send((sender, nodes));
+            }
+            Message::Get { .. }
+            | Message::GetAck { .. }
+            | Message::GetPieces { .. }
+            | Message::Search { .. }
+            | Message::SearchResults { .. } => {
+                let _ = get_server_tx.send(data_addr_pair);
             }
-        } else {
-            info!("Packet was not recognized!");
+            _ => info!("Packet was not recognized!"),
         }
     }
 }
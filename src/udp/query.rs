@@ -0,0 +1,87 @@
+// Query routing for flooded GETs and SEARCHes.
+//
+// Every query carries a random id, and every node remembers for a while where each id first
+// came from. Copies arriving later over other paths are dropped, which is what stops loops,
+// and answers follow those memories hop by hop back to the node that asked.
+use super::message::Message;
+use crate::context::NodeContext;
+use crate::networking::QUERY_MEMORY_MS;
+use std::collections::HashMap;
+use std::net::{SocketAddr, SocketAddrV4};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Route {
+    // We asked, so the answers are ours.
+    Origin,
+    // We forwarded it, so the answers go back to whoever sent it to us.
+    Upstream(SocketAddr),
+}
+
+// Where each recent query came from, and when.
+pub type Routes = Mutex<HashMap<u128, (Route, Instant)>>;
+
+// Picks an id for a query of our own.
+pub fn new_query(ctx: &NodeContext) -> u128 {
+    let query_id = rand::random();
+    remember(ctx, query_id, Route::Origin);
+    query_id
+}
+
+// Whether this is the first copy of the query to reach us, remembering where it came from if so.
+pub fn first_sighting(ctx: &NodeContext, query_id: u128, sender: SocketAddr) -> bool {
+    remember(ctx, query_id, Route::Upstream(sender))
+}
+
+// Where an answer to the query has to go, if we still remember the query at all.
+pub fn route_of(ctx: &NodeContext, query_id: u128) -> Option<Route> {
+    route_in(&ctx.routes, query_id)
+}
+
+pub fn route_in(routes: &Routes, query_id: u128) -> Option<Route> {
+    routes
+        .lock()
+        .unwrap()
+        .get(&query_id)
+        .map(|(route, _)| *route)
+}
+
+fn remember(ctx: &NodeContext, query_id: u128, route: Route) -> bool {
+    remember_at(&ctx.routes, query_id, route, Instant::now())
+}
+
+// Keeps the first route of a query, returning whether this was it.
+pub fn remember_at(routes: &Routes, query_id: u128, route: Route, now: Instant) -> bool {
+    let memory = Duration::from_millis(QUERY_MEMORY_MS);
+    let mut routes_ptr = routes.lock().unwrap();
+    routes_ptr.retain(|_, (_, seen_at)| now.saturating_duration_since(*seen_at) < memory);
+    if routes_ptr.contains_key(&query_id) {
+        return false;
+    }
+    routes_ptr.insert(query_id, (route, now));
+    true
+}
+
+// The copy of a GET or SEARCH passed on to our other peers, while it has hops left.
+pub fn next_hop(query: &Message) -> Option<Message> {
+    let mut forwarded = query.clone();
+    match &mut forwarded {
+        Message::Get { ttl, .. } | Message::Search { ttl, .. } if *ttl > 1 => *ttl -= 1,
+        _ => return None,
+    }
+    Some(forwarded)
+}
+
+// The copy of an answer passed back upstream, naming whoever answered so the origin
+// can reach it directly.
+pub fn relayed(answer: &Message, responder: SocketAddrV4) -> Option<Message> {
+    let mut forwarded = answer.clone();
+    match &mut forwarded {
+        Message::GetAck { responder: r, .. } | Message::SearchResults { responder: r, .. } => {
+            *r = responder
+        }
+        _ => return None,
+    }
+    Some(forwarded)
+}
@@ -0,0 +1,208 @@
+use super::session::{
+    linger, next_data_packet, open_session_socket, parse_intact, recv_in_time, retries_exhausted,
+    windowed_server, ClientTimer,
+};
+use crate::context::NodeContext;
+use crate::dir::FileRange;
+use crate::networking::{delay_to_avoid_surfers, BUF_SIZE};
+use crate::node;
+use crate::udp::headers::{FrameHeader, PacketHeader};
+use crate::udp::link::LinkSocket;
+use crate::udp::message::Message;
+use crate::udp::rtt;
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{self, sleep};
+use tokio_util::sync::CancellationToken;
+
+pub async fn gbn_server(
+    ctx: Arc<NodeContext>,
+    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    windowed_server(
+        ctx,
+        nodes_arc,
+        &[PacketHeader::GoBackN],
+        |ctx, socket, receiver, prior_comms, rdt_addr, session, file_range| {
+            Box::pin(gbn_sender(
+                ctx,
+                socket,
+                receiver,
+                prior_comms,
+                rdt_addr,
+                session,
+                file_range,
+            ))
+        },
+        shutdown,
+    )
+    .await
+}
+
+pub async fn gbn_sender(
+    ctx: Arc<NodeContext>,
+    socket: Arc<LinkSocket>,
+    mut receiver: UnboundedReceiver<Vec<u8>>,
+    prior_comms: u16,
+    rdt_addr: SocketAddr,
+    session: u32,
+    mut file_input_stream: FileRange,
+) -> std::io::Result<()> {
+    let window_size = ctx.config.window_size;
+    let mut rtt = rtt::estimator_for(&ctx.rtts, rdt_addr);
+    let anti_surfing_interval = Duration::from_millis(delay_to_avoid_surfers(prior_comms));
+    // Every sent but not yet ACK'd packet and when it was sent, oldest (seq == base) first.
+    let mut window: VecDeque<(Vec<u8>, Instant)> = VecDeque::new();
+    let mut base: u32 = 0;
+    let mut next_seq: u32 = 0;
+    // Packets below this were resent at some point, so their ACKs can't be timed.
+    let mut resent_below: u32 = 0;
+    let mut finished_reading = false;
+    let mut retries = 0;
+    let mut acked_since_pause: u32 = 0;
+    let mut corrupt_packet_count = 0;
+    // A single timer, running for the oldest unACK'd packet.
+    let mut timer = Instant::now();
+    loop {
+        while !finished_reading && next_seq < base.saturating_add(window_size) {
+            let (packet, is_end) = next_data_packet(
+                &mut file_input_stream,
+                session,
+                next_seq,
+                PacketHeader::GoBackN,
+            )
+            .await?;
+            socket.send_to(&packet, rdt_addr).await?;
+            if window.is_empty() {
+                timer = Instant::now();
+            }
+            window.push_back((packet, Instant::now()));
+            next_seq += 1;
+            finished_reading = is_end;
+        }
+        if window.is_empty() {
+            info!(
+                "Go-Back-N transfer to {} is complete, {} corrupted ACKs",
+                rdt_addr, corrupt_packet_count
+            );
+            rtt::remember(&ctx.rtts, rdt_addr, &rtt);
+            return Ok(());
+        }
+        let remaining = rtt.rto().checked_sub(timer.elapsed()).unwrap_or_default();
+        match time::timeout(remaining, receiver.recv()).await {
+            Ok(Some(packet)) => {
+                let ack = match parse_intact(&packet, session, &mut corrupt_packet_count) {
+                    Ok((ack, _)) if ack.header_type == PacketHeader::GoBackN => ack,
+                    _ => continue,
+                };
+                // ACKs are cumulative: they carry the next sequence number the receiver expects.
+                if ack.seq > base && ack.seq <= next_seq {
+                    info!("Received ACK {}", ack.seq);
+                    let newest_acked = ack.seq - 1;
+                    if newest_acked >= resent_below {
+                        let (_, sent_at) = window[(newest_acked - base) as usize];
+                        rtt.sample(sent_at.elapsed());
+                    }
+                    rtt.restart();
+                    window.drain(..(ack.seq - base) as usize);
+                    acked_since_pause += ack.seq - base;
+                    base = ack.seq;
+                    retries = 0;
+                    // Surfers are slowed down once per window, not once per packet.
+                    if acked_since_pause >= window_size {
+                        acked_since_pause = 0;
+                        sleep(anti_surfing_interval).await;
+                    }
+                    timer = Instant::now();
+                }
+            }
+            Err(_) => {
+                retries += 1;
+                retries_exhausted(retries)?;
+                rtt.back_off();
+                info!("Timed out on {}, resending {} packets", base, window.len());
+                for (packet, _) in &window {
+                    socket.send_to(packet, rdt_addr).await?;
+                }
+                resent_below = next_seq;
+                timer = Instant::now();
+            }
+            Ok(None) => return Ok(()),
+        }
+    }
+}
+
+pub async fn gbn_client(
+    ctx: Arc<NodeContext>,
+    sender_addr: SocketAddr,
+    file_name: String,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<Vec<u8>> {
+    let session = rand::random();
+    info!("Trying to connect to GBN Data Socket: {}", sender_addr);
+    let get_request = Message::RdtGet {
+        get_port: ctx.control_port(),
+        session,
+        offset,
+        length: Some(length),
+        file_name,
+    }
+    .encode()?;
+    let socket = open_session_socket(&ctx, sender_addr, &get_request).await?;
+    let mut timer = ClientTimer::new(rtt::estimator_for(&ctx.rtts, sender_addr));
+    let mut received = Vec::with_capacity(length as usize);
+    let mut expected: u32 = 0;
+    let mut retries = 0;
+    let mut corrupt_packet_count = 0;
+    let mut buf = [0; BUF_SIZE];
+    loop {
+        let size = match recv_in_time(&socket, &mut buf, timer.rto()).await {
+            Ok(size) => size,
+            Err(_) => {
+                timer.timed_out();
+                retries += 1;
+                retries_exhausted(retries)?;
+                // Nothing has arrived yet, so the GET itself might have been lost.
+                if expected == 0 {
+                    socket.send(&get_request).await?;
+                }
+                continue;
+            }
+        };
+        retries = 0;
+        timer.heard_back();
+        // Corrupted packets are dropped just like lost ones.
+        let (header, payload) = match parse_intact(&buf[..size], session, &mut corrupt_packet_count)
+        {
+            Ok(pair) => pair,
+            Err(_) => continue,
+        };
+        if header.header_type != PacketHeader::GoBackN {
+            continue;
+        }
+        // Anything out of order is dropped; the sender will go back and resend it.
+        if header.seq == expected {
+            if header.is_fin() {
+                info!(
+                    "Received FIN frame, {} corrupted packets",
+                    corrupt_packet_count
+                );
+                rtt::remember(&ctx.rtts, sender_addr, &timer.rtt);
+                let last_ack =
+                    FrameHeader::new(PacketHeader::GoBackN, session, expected + 1).as_vec();
+                socket.send(&last_ack).await?;
+                linger(&socket, &mut timer.rtt, |_| last_ack.clone()).await;
+                return Ok(received);
+            }
+            received.extend_from_slice(payload);
+            expected += 1;
+        }
+        let ack = FrameHeader::new(PacketHeader::GoBackN, session, expected);
+        socket.send(&ack.as_vec()).await?;
+    }
+}
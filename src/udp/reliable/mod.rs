@@ -1,2 +1,7 @@
+mod gobackn;
+mod session;
+mod srepeat;
 mod stopwait;
-pub use stopwait::{sw_client, sw_server};
\ No newline at end of file
+pub use gobackn::{gbn_client, gbn_server};
+pub use srepeat::{sr_client, sr_server};
+pub use stopwait::{sw_client, sw_server};
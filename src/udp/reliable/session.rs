@@ -0,0 +1,301 @@
+use crate::context::NodeContext;
+use crate::dir::{index, open_range, FileRange};
+use crate::networking::{
+    bind_udp_socket, check_clients, ip_port_string, spawn_until, BUF_SIZE, RDT_MAX_RETRIES,
+};
+use crate::node;
+use crate::udp::headers::{
+    FrameError, FrameHeader, PacketHeader, FRAME_HEADER_SIZE, RDT_HEADER_SIZE,
+};
+use crate::udp::link::LinkSocket;
+use crate::udp::message::Message;
+use crate::udp::rtt::RttEstimator;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::future::Future;
+use std::io::{Error, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncReadExt;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+pub type SessionFuture = Pin<Box<dyn Future<Output = std::io::Result<()>> + Send>>;
+
+// What every windowed sender task gets: its node, the shared data socket, its share of the incoming
+// packets, how many downloads the requester started before this one, its address, the session
+// its frames carry and the range it wants.
+pub type SessionSender = fn(
+    Arc<NodeContext>,
+    Arc<LinkSocket>,
+    UnboundedReceiver<Vec<u8>>,
+    u16,
+    SocketAddr,
+    u32,
+    FileRange,
+) -> SessionFuture;
+
+// A running sender task, and the session its GET named.
+struct Session {
+    id: u32,
+    sender: UnboundedSender<Vec<u8>>,
+}
+
+// Serves the reliable UDP transports: RDT GETs start a new sender task, and the
+// raw datagrams that follow go to it so each transport can parse its own ACK format.
+pub async fn windowed_server(
+    ctx: Arc<NodeContext>,
+    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+    ack_types: &[PacketHeader],
+    session: SessionSender,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    let socket = Arc::new(LinkSocket::new(
+        bind_udp_socket(ctx.config.ip, ctx.config.data_sender_port).await,
+        ctx.config.link,
+    ));
+    ctx.set_data_port(socket.local_addr()?.port());
+    let mut sessions: HashMap<String, Session> = HashMap::new();
+    let mut buf = [0; BUF_SIZE];
+    loop {
+        // This function is the only one reading from the socket!
+        // Some platforms report an unreachable peer on the next receive, which is no reason to stop.
+        let (size, addr) = match socket.recv_from(&mut buf).await {
+            Ok(pair) => pair,
+            Err(e) => {
+                warn!("Couldn't receive on the data socket: {}", e);
+                continue;
+            }
+        };
+        let packet = &buf[..size];
+        let header_ip = match addr.ip() {
+            IpAddr::V4(v4) => v4,
+            IpAddr::V6(_) => continue,
+        };
+        let client_rdt_address = ip_port_string(header_ip, addr.port());
+        let tag = std::str::from_utf8(&packet[..size.min(RDT_HEADER_SIZE as usize)]).unwrap_or("");
+        let header_type = PacketHeader::packet_type(tag);
+        if ack_types.contains(&header_type) {
+            let session = match sessions.get(&client_rdt_address) {
+                Some(session) => session,
+                None => continue,
+            };
+            // The session is over, so there's no one left to hear it.
+            if session.sender.send(packet.to_vec()).is_err() {
+                sessions.remove(&client_rdt_address);
+            }
+            continue;
+        }
+        let (get_port, session_id, offset, length, file_name) = match Message::decode(packet) {
+            Ok(Message::RdtGet {
+                get_port,
+                session,
+                offset,
+                length,
+                file_name,
+            }) => (get_port, session, offset, length, file_name),
+            Ok(_) => {
+                info!("Packet was not recognized!");
+                continue;
+            }
+            Err(e) => {
+                warn!("Rejected packet from {}: {}", client_rdt_address, e);
+                continue;
+            }
+        };
+        // A repeated GET of a running session means our first packets got lost, and its timer
+        // already takes care of that. Clients fetch every piece from the same port though, so a
+        // GET of another session is the next piece, and whatever is left of the last one is dropped.
+        if let Some(session) = sessions.get(&client_rdt_address) {
+            if session.id == session_id && !session.sender.is_closed() {
+                info!("Ignoring a repeated RDT GET from {}", client_rdt_address);
+                continue;
+            }
+        }
+        info!("Received RDT GET packet");
+        let checked = check_clients(&ctx, header_ip, get_port, &file_name, nodes_arc.clone());
+        let (was_sneaky, prior_comms) = match checked {
+            Ok(pair) => pair,
+            Err(e) => {
+                warn!("Refused {}: {}", client_rdt_address, e);
+                continue;
+            }
+        };
+        if !was_sneaky || index::lookup_async(&ctx, &file_name).await.is_some() {
+            let file_range = match open_range(&ctx.config, &file_name, offset, length).await {
+                Ok(range) => range,
+                Err(e) => {
+                    warn!("Can't serve {} to {}: {}", file_name, client_rdt_address, e);
+                    continue;
+                }
+            };
+            let (sender, receiver) = mpsc::unbounded_channel::<Vec<u8>>();
+            // Dropping the last session's sender ends it.
+            let session_entry = Session {
+                id: session_id,
+                sender,
+            };
+            sessions.insert(client_rdt_address.clone(), session_entry);
+            info!(
+                "Spawning a new sender task for socket: {}",
+                client_rdt_address
+            );
+            let transfer = session(
+                ctx.clone(),
+                socket.clone(),
+                receiver,
+                prior_comms,
+                addr,
+                session_id,
+                file_range,
+            );
+            spawn_until(&shutdown, async move {
+                if let Err(e) = transfer.await {
+                    warn!("Transfer to {} failed: {}", client_rdt_address, e);
+                }
+            });
+        }
+    }
+}
+
+// Drops and counts frames that got damaged on the way, or that a peer we can't understand sent.
+// Intact frames of another session are dropped too, they're late ones of an earlier transfer.
+pub fn parse_intact<'a>(
+    packet: &'a [u8],
+    session: u32,
+    corrupt_packet_count: &mut u32,
+) -> Result<(FrameHeader, &'a [u8]), FrameError> {
+    let result = FrameHeader::from_bytes(packet).and_then(|(header, payload)| {
+        if header.session == session {
+            Ok((header, payload))
+        } else {
+            Err(FrameError::Session(header.session))
+        }
+    });
+    match &result {
+        Ok(_) => {}
+        Err(FrameError::Version(version)) => {
+            warn!("Dropped a frame of unsupported version {}", version);
+        }
+        Err(FrameError::Session(other)) => {
+            info!("Dropped a late frame of session {}", other);
+        }
+        Err(FrameError::Truncated) | Err(FrameError::Corrupted) => {
+            *corrupt_packet_count += 1;
+            warn!(
+                "Dropped a corrupted packet ({} so far in this transfer)",
+                corrupt_packet_count
+            );
+        }
+    }
+    result
+}
+
+// Reads the next chunk of the file and frames it; an empty chunk becomes the FIN frame.
+pub async fn next_data_packet(
+    input: &mut FileRange,
+    session: u32,
+    seq: u32,
+    data_type: PacketHeader,
+) -> std::io::Result<(Vec<u8>, bool)> {
+    let mut buf = [0; BUF_SIZE - FRAME_HEADER_SIZE];
+    let size = input.read(&mut buf).await?;
+    if size == 0 {
+        return Ok((FrameHeader::fin(data_type, session, seq).as_vec(), true));
+    }
+    let data_header = FrameHeader::new(data_type, session, seq);
+    Ok((data_header.with_payload(&buf[..size]), false))
+}
+
+// Binds a receiving socket, ties it to the sender and asks for the file.
+pub async fn open_session_socket(
+    ctx: &NodeContext,
+    sender_addr: SocketAddr,
+    get_request: &[u8],
+) -> std::io::Result<LinkSocket> {
+    let socket = bind_udp_socket(ctx.config.ip, ctx.config.data_receiver_port).await;
+    // Making the UDP connection "duplex".
+    socket.connect(sender_addr).await?;
+    let socket = LinkSocket::new(socket, ctx.config.link);
+    socket.send(get_request).await?;
+    Ok(socket)
+}
+
+// Waits for the next datagram from the sender, for at most one retransmission timeout.
+pub async fn recv_in_time(
+    socket: &LinkSocket,
+    buf: &mut [u8],
+    rto: Duration,
+) -> std::io::Result<usize> {
+    match timeout(rto, socket.recv(buf)).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "nothing arrived in time")),
+    }
+}
+
+pub fn retries_exhausted(retries: u16) -> std::io::Result<()> {
+    if retries > RDT_MAX_RETRIES {
+        warn!("Peer stopped responding, giving up on the transfer");
+        return Err(Error::new(
+            ErrorKind::TimedOut,
+            "reliable UDP peer stopped responding",
+        ));
+    }
+    Ok(())
+}
+
+// Keep answering retransmissions for a while, in case some of our last ACKs got lost.
+// The sender backs off with every one of them, and so do we.
+pub async fn linger<F: Fn(&[u8]) -> Vec<u8>>(
+    socket: &LinkSocket,
+    rtt: &mut RttEstimator,
+    ack_for: F,
+) {
+    let mut buf = [0; BUF_SIZE];
+    while let Ok(size) = recv_in_time(socket, &mut buf, rtt.rto() * 2).await {
+        let _ = socket.send(&ack_for(&buf[..size])).await;
+        rtt.back_off();
+    }
+}
+
+// The client's side of a session: it only ever times the GET, and backs off while the
+// sender is silent, so a slow sender isn't given up on too early.
+pub struct ClientTimer {
+    pub rtt: RttEstimator,
+    get_sent_at: Instant,
+    get_resent: bool,
+    heard_back: bool,
+}
+
+impl ClientTimer {
+    pub fn new(rtt: RttEstimator) -> ClientTimer {
+        ClientTimer {
+            rtt,
+            get_sent_at: Instant::now(),
+            get_resent: false,
+            heard_back: false,
+        }
+    }
+
+    pub fn rto(&self) -> Duration {
+        self.rtt.rto()
+    }
+
+    pub fn timed_out(&mut self) {
+        self.rtt.back_off();
+        if !self.heard_back {
+            self.get_resent = true;
+        }
+    }
+
+    pub fn heard_back(&mut self) {
+        if !self.heard_back && !self.get_resent {
+            self.rtt.sample(self.get_sent_at.elapsed());
+        }
+        self.rtt.restart();
+        self.heard_back = true;
+    }
+}
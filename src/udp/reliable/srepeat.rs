@@ -0,0 +1,226 @@
+use super::session::{
+    linger, next_data_packet, open_session_socket, parse_intact, recv_in_time, retries_exhausted,
+    windowed_server, ClientTimer,
+};
+use crate::context::NodeContext;
+use crate::dir::FileRange;
+use crate::networking::{delay_to_avoid_surfers, BUF_SIZE};
+use crate::node;
+use crate::udp::headers::{FrameHeader, PacketHeader};
+use crate::udp::link::LinkSocket;
+use crate::udp::message::Message;
+use crate::udp::rtt;
+use std::collections::{BTreeMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{self, sleep};
+use tokio_util::sync::CancellationToken;
+
+pub async fn sr_server(
+    ctx: Arc<NodeContext>,
+    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    windowed_server(
+        ctx,
+        nodes_arc,
+        &[PacketHeader::SRepeat],
+        |ctx, socket, receiver, prior_comms, rdt_addr, session, file_range| {
+            Box::pin(sr_sender(
+                ctx,
+                socket,
+                receiver,
+                prior_comms,
+                rdt_addr,
+                session,
+                file_range,
+            ))
+        },
+        shutdown,
+    )
+    .await
+}
+
+pub async fn sr_sender(
+    ctx: Arc<NodeContext>,
+    socket: Arc<LinkSocket>,
+    mut receiver: UnboundedReceiver<Vec<u8>>,
+    prior_comms: u16,
+    rdt_addr: SocketAddr,
+    session: u32,
+    mut file_input_stream: FileRange,
+) -> std::io::Result<()> {
+    let window_size = ctx.config.window_size;
+    let mut rtt = rtt::estimator_for(&ctx.rtts, rdt_addr);
+    let anti_surfing_interval = Duration::from_millis(delay_to_avoid_surfers(prior_comms));
+    // Every sent but not yet ACK'd packet, along with when it was last (re)sent and whether
+    // it ever was resent, since only the ones sent once can be timed.
+    let mut window: BTreeMap<u32, (Vec<u8>, Instant, bool)> = BTreeMap::new();
+    let mut base: u32 = 0;
+    let mut next_seq: u32 = 0;
+    let mut finished_reading = false;
+    let mut retries = 0;
+    let mut acked_since_pause: u32 = 0;
+    let mut corrupt_packet_count = 0;
+    loop {
+        while !finished_reading && next_seq < base.saturating_add(window_size) {
+            let (packet, is_end) = next_data_packet(
+                &mut file_input_stream,
+                session,
+                next_seq,
+                PacketHeader::SRepeat,
+            )
+            .await?;
+            socket.send_to(&packet, rdt_addr).await?;
+            window.insert(next_seq, (packet, Instant::now(), false));
+            next_seq += 1;
+            finished_reading = is_end;
+        }
+        // The earliest running timer decides how long we may wait for ACKs.
+        let oldest = match window.values().map(|(_, sent_at, _)| *sent_at).min() {
+            Some(sent_at) => sent_at,
+            None => {
+                info!(
+                    "Selective Repeat transfer to {} is complete, {} corrupted ACKs",
+                    rdt_addr, corrupt_packet_count
+                );
+                rtt::remember(&ctx.rtts, rdt_addr, &rtt);
+                return Ok(());
+            }
+        };
+        let rto = rtt.rto();
+        let remaining = rto.checked_sub(oldest.elapsed()).unwrap_or_default();
+        match time::timeout(remaining, receiver.recv()).await {
+            Ok(Some(packet)) => {
+                let ack = match parse_intact(&packet, session, &mut corrupt_packet_count) {
+                    Ok((ack, _)) if ack.header_type == PacketHeader::SRepeat => ack,
+                    _ => continue,
+                };
+                if let Some((_, sent_at, resent)) = window.remove(&ack.seq) {
+                    info!("Received ACK {}", ack.seq);
+                    if !resent {
+                        rtt.sample(sent_at.elapsed());
+                    }
+                    rtt.restart();
+                    retries = 0;
+                    acked_since_pause += 1;
+                    base = window.keys().next().copied().unwrap_or(next_seq);
+                    // Surfers are slowed down once per window, not once per packet.
+                    if acked_since_pause >= window_size {
+                        acked_since_pause = 0;
+                        sleep(anti_surfing_interval).await;
+                    }
+                }
+            }
+            Err(_) => {
+                retries += 1;
+                retries_exhausted(retries)?;
+                rtt.back_off();
+                // Only the packets whose own timers ran out are resent.
+                for (seq, (packet, sent_at, resent)) in window.iter_mut() {
+                    if sent_at.elapsed() >= rto {
+                        info!("Timed out on {}, resending it", seq);
+                        socket.send_to(packet, rdt_addr).await?;
+                        *sent_at = Instant::now();
+                        *resent = true;
+                    }
+                }
+            }
+            Ok(None) => return Ok(()),
+        }
+    }
+}
+
+fn sr_ack(session: u32, seq: u32) -> Vec<u8> {
+    FrameHeader::new(PacketHeader::SRepeat, session, seq).as_vec()
+}
+
+pub async fn sr_client(
+    ctx: Arc<NodeContext>,
+    sender_addr: SocketAddr,
+    file_name: String,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<Vec<u8>> {
+    let session = rand::random();
+    info!("Trying to connect to SR Data Socket: {}", sender_addr);
+    let get_request = Message::RdtGet {
+        get_port: ctx.control_port(),
+        session,
+        offset,
+        length: Some(length),
+        file_name,
+    }
+    .encode()?;
+    let socket = open_session_socket(&ctx, sender_addr, &get_request).await?;
+    let mut timer = ClientTimer::new(rtt::estimator_for(&ctx.rtts, sender_addr));
+    let mut received = Vec::with_capacity(length as usize);
+    let window_size = ctx.config.window_size;
+    // Packets that arrived ahead of rcv_base, waiting for the gap before them to fill.
+    // Each one is kept along with whether it was the FIN frame.
+    let mut reorder_buffer: BTreeMap<u32, (bool, Vec<u8>)> = BTreeMap::new();
+    let mut rcv_base: u32 = 0;
+    let mut retries = 0;
+    let mut corrupt_packet_count = 0;
+    let mut buf = [0; BUF_SIZE];
+    loop {
+        let size = match recv_in_time(&socket, &mut buf, timer.rto()).await {
+            Ok(size) => size,
+            Err(_) => {
+                timer.timed_out();
+                retries += 1;
+                retries_exhausted(retries)?;
+                // Nothing has arrived yet, so the GET itself might have been lost.
+                if rcv_base == 0 && reorder_buffer.is_empty() {
+                    socket.send(&get_request).await?;
+                }
+                continue;
+            }
+        };
+        retries = 0;
+        timer.heard_back();
+        // Corrupted packets are dropped unACK'd, their timers will bring them back.
+        let (header, payload) = match parse_intact(&buf[..size], session, &mut corrupt_packet_count)
+        {
+            Ok(pair) => pair,
+            Err(_) => continue,
+        };
+        if header.header_type != PacketHeader::SRepeat {
+            continue;
+        }
+        if header.seq >= rcv_base.saturating_add(window_size) {
+            // Beyond our window: drop it unACK'd, its timer will bring it back.
+            continue;
+        }
+        // Already delivered packets are ACK'd again, since our first ACK might have been lost.
+        socket.send(&sr_ack(session, header.seq)).await?;
+        if header.seq >= rcv_base {
+            reorder_buffer
+                .entry(header.seq)
+                .or_insert((header.is_fin(), payload.to_vec()));
+        }
+        while let Some((is_fin, payload)) = reorder_buffer.remove(&rcv_base) {
+            if is_fin {
+                info!(
+                    "Received FIN frame, {} corrupted packets",
+                    corrupt_packet_count
+                );
+                rtt::remember(&ctx.rtts, sender_addr, &timer.rtt);
+                linger(
+                    &socket,
+                    &mut timer.rtt,
+                    |packet| match FrameHeader::from_bytes(packet) {
+                        Ok((late, _)) => sr_ack(session, late.seq),
+                        Err(_) => sr_ack(session, rcv_base),
+                    },
+                )
+                .await;
+                return Ok(received);
+            }
+            received.extend_from_slice(&payload);
+            rcv_base += 1;
+        }
+    }
+}
@@ -1,210 +1,212 @@
-use crate::dir::{file_list, generate_file_address};
-use crate::networking::bind_udp_socket;
-use crate::networking::{
-    self, check_clients, delay_to_avoid_surfers, ip_port_string, BUF_SIZE, DATA_RECEIVER_PORT,
-    UDP_GET_PORT,
+use super::session::{
+    linger, next_data_packet, open_session_socket, parse_intact, recv_in_time, retries_exhausted,
+    windowed_server, ClientTimer,
 };
+use crate::context::NodeContext;
+use crate::dir::FileRange;
+use crate::networking::{delay_to_avoid_surfers, BUF_SIZE};
 use crate::node;
-use crate::udp::headers::{PacketHeader, StopAndWaitHeader, RDT_HEADER_SIZE};
-use crate::NODE_IP;
-use std::collections::HashMap;
+use crate::udp::headers::{FrameError, FrameHeader, PacketHeader};
+use crate::udp::link::LinkSocket;
+use crate::udp::message::Message;
+use crate::udp::rtt;
 use std::collections::HashSet;
-use std::fs::File;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::{IpAddr, SocketAddr, UdpSocket};
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::net::SocketAddr;
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
-use std::{thread, time};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::{self, sleep};
+use tokio_util::sync::CancellationToken;
 
-pub fn sw_server(nodes_arc: Arc<RwLock<HashSet<node::Node>>>) -> std::io::Result<()> {
-    let socket = bind_udp_socket(*networking::DATA_SENDER_PORT, false);
-    let mut nodes_channels: HashMap<String, Sender<(StopAndWaitHeader, Vec<u8>)>> = HashMap::new();
-    let mut buf = [0; BUF_SIZE];
-    loop {
-        // This function is the only one reading from the socket!
-        let (_, addr) = socket.recv_from(&mut buf).unwrap();
-        let buf_clone = buf.clone();
-        let (header, data) = StopAndWaitHeader::from_bytes(&buf_clone);
-        let header_ip = match addr.ip() {
-            IpAddr::V4(v4) => v4,
-            // Cause who tf is using Ipv6 with this?
-            IpAddr::V6(_) => return Ok(()),
-        };
-        let client_rdt_port = addr.port();
-        let client_rdt_address = ip_port_string(header_ip, client_rdt_port);
-        if header.header_type == PacketHeader::RDTGET {
-            info!("Received S&W GET packet");
-            let (was_sneaky, prior_comms) =
-                check_clients(header_ip, header.get_port, nodes_arc.clone());
-            if !was_sneaky || file_list().iter().any(|x| x == &header.file_name) {
-                let (sender, receiver) = mpsc::channel::<(StopAndWaitHeader, Vec<u8>)>();
-                nodes_channels.insert(client_rdt_address.clone(), sender.clone());
-                // Spawn client_handler if it's a new node.
-                let new_socket = socket.try_clone().unwrap();
-                info!(
-                    "Spawning a new sender thread for socket: {}",
-                    client_rdt_address
-                );
-                std::thread::spawn(move || {
-                    sw_sender(new_socket, receiver, prior_comms, client_rdt_address)
-                });
-                sender.send((header, data.to_vec())).unwrap();
-            }
-        } else if header.header_type == PacketHeader::StopWaitACK
-            || header.header_type == PacketHeader::StopWaitNAK
-        {
-            let sender = match nodes_channels.get(&client_rdt_address) {
-                Some(snd) => snd,
-                None => continue,
-            };
-            sender.send((header, data.to_vec())).unwrap();
-        }
-    }
-}
-
-pub fn read_and_write(
-    input: &mut BufReader<File>,
-    buf: &mut [u8],
-    socket: &UdpSocket,
-    rdt_addr: &str,
-) -> std::io::Result<usize> {
-    info!("Sending file data through socket!");
-    let size = input.read(buf)?;
-    // 0 means that we have reached EOF.
-    if size == 0 {
-        info!("Finished reading and writing!");
-        socket
-            .send_to(PacketHeader::rdt_end().as_bytes(), &rdt_addr)
-            .unwrap();
-        return Ok(0);
-    } else {
-        socket.send_to(&buf[..size], rdt_addr).unwrap();
-    }
-    Ok(size)
+pub async fn sw_server(
+    ctx: Arc<NodeContext>,
+    nodes_arc: Arc<RwLock<HashSet<node::Node>>>,
+    shutdown: CancellationToken,
+) -> std::io::Result<()> {
+    windowed_server(
+        ctx,
+        nodes_arc,
+        &[PacketHeader::StopWaitACK, PacketHeader::StopWaitNAK],
+        |ctx, socket, receiver, prior_comms, rdt_addr, session, file_range| {
+            Box::pin(sw_sender(
+                ctx,
+                socket,
+                receiver,
+                prior_comms,
+                rdt_addr,
+                session,
+                file_range,
+            ))
+        },
+        shutdown,
+    )
+    .await
 }
 
-pub fn sw_sender(
-    socket: UdpSocket,
-    receiver: Receiver<(StopAndWaitHeader, Vec<u8>)>,
+pub async fn sw_sender(
+    ctx: Arc<NodeContext>,
+    socket: Arc<LinkSocket>,
+    mut receiver: UnboundedReceiver<Vec<u8>>,
     prior_comms: u16,
-    rdt_addr: String,
+    rdt_addr: SocketAddr,
+    session: u32,
+    mut file_input_stream: FileRange,
 ) -> std::io::Result<()> {
-    // Here, data is not important because we're the sender.
-    let (header, _) = receiver.recv().unwrap();
     info!("Received data from channel (as it should)");
-    let file_name = header.file_name;
-    let file_addr = generate_file_address(&file_name, false);
-    let f = File::open(&file_addr)?;
-    let mut file_input_stream = BufReader::new(f);
-    let get_port = header.get_port;
-    let mut buf = [0; BUF_SIZE];
+    let mut rtt = rtt::estimator_for(&ctx.rtts, rdt_addr);
+    let anti_surfing_interval = Duration::from_millis(delay_to_avoid_surfers(prior_comms));
+    let mut seq: u32 = 0;
     let mut corrupt_packet_count = 0;
-    let mut size: usize =
-        read_and_write(&mut file_input_stream, &mut buf, &socket, &rdt_addr).unwrap();
-    if size > 0 {
+    loop {
+        let (packet, is_end) = next_data_packet(
+            &mut file_input_stream,
+            session,
+            seq,
+            PacketHeader::StopWaitData,
+        )
+        .await?;
+        socket.send_to(&packet, rdt_addr).await?;
+        let mut retries = 0;
+        // Karn's algorithm: once resent, its ACK can't be timed.
+        let mut resent = false;
+        let mut timer = Instant::now();
+        info!("Waiting for client response");
         loop {
-            info!("Waiting for client response");
-            let (header, _) = receiver.recv().unwrap();
-            // Received new client response
-            let delay = delay_to_avoid_surfers(prior_comms);
-            let anti_surfing_interval = time::Duration::from_millis(delay);
-            thread::sleep(anti_surfing_interval);
-            info!("Finished sleeping");
-            if header.get_port == get_port && header.file_name == file_name {
-                if header.header_type == PacketHeader::StopWaitACK {
-                    info!("Received ACK");
-                    size = read_and_write(&mut file_input_stream, &mut buf, &socket, &rdt_addr)
-                        .unwrap();
-                    if size == 0 {
-                        return Ok(());
+            let remaining = rtt.rto().checked_sub(timer.elapsed()).unwrap_or_default();
+            match time::timeout(remaining, receiver.recv()).await {
+                Ok(Some(response)) => {
+                    // Responses about anything but the packet in flight are stale duplicates.
+                    match parse_intact(&response, session, &mut corrupt_packet_count) {
+                        Ok((ack, _))
+                            if ack.header_type == PacketHeader::StopWaitACK && ack.seq == seq =>
+                        {
+                            info!("Received ACK {}", seq);
+                            if !resent {
+                                rtt.sample(timer.elapsed());
+                            }
+                            rtt.restart();
+                            break;
+                        }
+                        Ok((nak, _))
+                            if nak.header_type == PacketHeader::StopWaitNAK && nak.seq == seq =>
+                        {
+                            info!("Received NAK {}, resending it", seq);
+                            socket.send_to(&packet, rdt_addr).await?;
+                            resent = true;
+                            timer = Instant::now();
+                        }
+                        _ => info!("Ignoring a stale response"),
                     }
-                    socket.send_to(&buf[..size], &rdt_addr).unwrap();
-                } else if header.header_type == PacketHeader::StopWaitNAK {
-                    info!("Received NAK");
-                    socket.send_to(&buf[..size], &rdt_addr).unwrap();
-                } else {
-                    info!("Something has gone wrong with the packet's header");
-                    corrupt_packet_count += 1;
                 }
-                if corrupt_packet_count > 5 {
-                    warn!("Too many faulty packets");
-                    // Well not really ok but it's run in a separate thread, so who cares?
-                    return Ok(());
+                Err(_) => {
+                    retries += 1;
+                    retries_exhausted(retries)?;
+                    rtt.back_off();
+                    info!("Timed out on {}, resending it", seq);
+                    socket.send_to(&packet, rdt_addr).await?;
+                    resent = true;
+                    timer = Instant::now();
                 }
-            } else {
-                info!("Conditions were not satisfied");
+                Ok(None) => return Ok(()),
             }
         }
+        if is_end {
+            info!(
+                "Stop-and-Wait transfer to {} is complete, {} corrupted ACKs",
+                rdt_addr, corrupt_packet_count
+            );
+            rtt::remember(&ctx.rtts, rdt_addr, &rtt);
+            return Ok(());
+        }
+        seq += 1;
+        sleep(anti_surfing_interval).await;
     }
-    Ok(())
 }
 
-pub fn three_headers(
-    get_port: u16,
-    file_name: &str,
-) -> (StopAndWaitHeader, StopAndWaitHeader, StopAndWaitHeader) {
-    let get_header = StopAndWaitHeader::new(PacketHeader::RDTGET, get_port, file_name);
-    let ack_header = StopAndWaitHeader::new(PacketHeader::StopWaitACK, get_port, file_name);
-    let nak_header = StopAndWaitHeader::new(PacketHeader::StopWaitNAK, get_port, file_name);
-    (get_header, ack_header, nak_header)
+fn sw_ack(session: u32, seq: u32) -> Vec<u8> {
+    FrameHeader::new(PacketHeader::StopWaitACK, session, seq).as_vec()
+}
+
+fn sw_nak(session: u32, seq: u32) -> Vec<u8> {
+    FrameHeader::new(PacketHeader::StopWaitNAK, session, seq).as_vec()
 }
 
-pub fn sw_client(sender_addr: SocketAddr, file_name: String) -> std::io::Result<()> {
+pub async fn sw_client(
+    ctx: Arc<NodeContext>,
+    sender_addr: SocketAddr,
+    file_name: String,
+    offset: u64,
+    length: u64,
+) -> std::io::Result<Vec<u8>> {
+    let session = rand::random();
     info!("Trying to connect to S&W Data Socket: {}", sender_addr);
-    let file_addr = generate_file_address(&file_name, true);
-    let localhost = IpAddr::V4(*NODE_IP.read().unwrap());
-    let recv_addr = SocketAddr::new(localhost, *DATA_RECEIVER_PORT);
-    let socket = UdpSocket::bind(recv_addr).unwrap();
-    let f = File::create(file_addr).unwrap();
-    let mut file_output_stream = BufWriter::new(f);
-    // Making the UDP connection "duplex".
-    socket.connect(sender_addr).unwrap();
-    let timeout: Duration = Duration::new(3, 0);
-    socket.set_write_timeout(Some(timeout)).unwrap();
-    socket.set_read_timeout(Some(timeout)).unwrap();
-    let (get_header, ack_header, nak_header) = three_headers(UDP_GET_PORT, &file_name);
-    // Send data GET packet
-    let failure_addr = SocketAddr::new(localhost, 0);
-    info!("The udp get packet is: {}", get_header.as_string());
-    info!(
-        "Packet size in bytes: {}",
-        get_header.as_string().as_bytes().len()
-    );
-    let get_header_vec = get_header.as_vec();
-    let get_header_slice = get_header_vec.as_slice();
-    let ack_header_vec = ack_header.as_vec();
-    let ack_header_slice = ack_header_vec.as_slice();
-    let nak_header_vec = nak_header.as_vec();
-    let nak_header_slice = nak_header_vec.as_slice();
-    socket.send(get_header_slice).unwrap();
+    let get_request = Message::RdtGet {
+        get_port: ctx.control_port(),
+        session,
+        offset,
+        length: Some(length),
+        file_name,
+    }
+    .encode()?;
+    let socket = open_session_socket(&ctx, sender_addr, &get_request).await?;
+    let mut timer = ClientTimer::new(rtt::estimator_for(&ctx.rtts, sender_addr));
+    let mut received = Vec::with_capacity(length as usize);
+    let mut expected: u32 = 0;
+    let mut retries = 0;
+    let mut corrupt_packet_count = 0;
+    let mut buf = [0; BUF_SIZE];
     loop {
-        let mut buf = [0; BUF_SIZE];
         // No malicious packet can come through because we've connected it to one target!
-        let (size, data_addr) = match socket.recv_from(&mut buf) {
-            Ok((size, addr)) => (size, addr),
-            Err(_) => (0, failure_addr),
+        let size = match recv_in_time(&socket, &mut buf, timer.rto()).await {
+            Ok(size) => size,
+            Err(_) => {
+                timer.timed_out();
+                retries += 1;
+                retries_exhausted(retries)?;
+                // Nothing has arrived yet, so the GET itself might have been lost.
+                if expected == 0 {
+                    socket.send(&get_request).await?;
+                }
+                continue;
+            }
         };
+        retries = 0;
+        timer.heard_back();
         info!("Read {} bytes from socket", size);
-        if PacketHeader::is_end(&buf[..RDT_HEADER_SIZE as usize]) {
-            info!("Received END packet");
-            drop(file_output_stream);
-            return Ok(());
+        let (header, payload) = match parse_intact(&buf[..size], session, &mut corrupt_packet_count)
+        {
+            Ok(pair) => pair,
+            // Late frames of an earlier transfer aren't ours to NAK.
+            Err(FrameError::Session(_)) => continue,
+            Err(_) => {
+                // Its own sequence number can't be trusted, but it can only be the one we expect.
+                info!("Sending NAK");
+                socket.send(&sw_nak(session, expected)).await?;
+                continue;
+            }
+        };
+        // A packet from the future can't exist in S&W, so it's garbage.
+        if header.header_type != PacketHeader::StopWaitData || header.seq > expected {
+            continue;
         }
-        if data_addr == sender_addr {
-            info!("Received new data from server!");
-            if size > 0 {
-                info!("Sending ACK");
-                socket.send(ack_header_slice).unwrap();
-                let buf_clone = buf.clone();
-                file_output_stream.write(&buf_clone[..size]).unwrap();
+        if header.seq == expected {
+            if header.is_fin() {
+                info!(
+                    "Received FIN frame, {} corrupted packets",
+                    corrupt_packet_count
+                );
+                rtt::remember(&ctx.rtts, sender_addr, &timer.rtt);
+                let last_ack = sw_ack(session, expected);
+                socket.send(&last_ack).await?;
+                linger(&socket, &mut timer.rtt, |_| last_ack.clone()).await;
+                return Ok(received);
             }
-        } else {
-            info!("Sending NAK");
-            info!("Data addr is: {}", data_addr);
-            info!("Supposed addr is: {}", sender_addr);
-            socket.send(nak_header_slice).unwrap();
+            info!("Received new data from server!");
+            received.extend_from_slice(payload);
+            expected += 1;
         }
+        // Duplicates are ACK'd again but never written, our previous ACK was lost.
+        info!("Sending ACK");
+        socket.send(&sw_ack(session, header.seq)).await?;
     }
-    Ok(())
 }
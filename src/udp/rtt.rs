@@ -0,0 +1,97 @@
+// Retransmission timeouts picked the way TCP picks them (RFC 6298, after Jacobson and Karels).
+//
+// Every session learns a smoothed round trip time and how much it strays from it, and waits
+// that plus four times the straying before it retransmits. Each expiry doubles the wait until
+// the peer is heard from again, and only packets that were sent once give samples (Karn's
+// algorithm), since nobody can tell which copy of a resent one an ACK is for.
+// A download is a session per piece, so what one session learned is where the next one
+// with the same peer starts from.
+use crate::networking::{RDT_MAX_RTO_MS, RDT_MIN_RTO_MS, RDT_TIMEOUT_MS};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// Below this, the variance term of the timeout stops mattering.
+const CLOCK_GRANULARITY: Duration = Duration::from_millis(1);
+
+// What the sessions with each peer have learned, by the address they exchange frames with.
+pub type PeerRtts = Mutex<HashMap<SocketAddr, RttEstimator>>;
+
+#[derive(Clone, Copy)]
+pub struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    // The timeout the samples so far call for, before any backing off.
+    base_rto: Duration,
+    backoff: u32,
+}
+
+impl Default for RttEstimator {
+    fn default() -> RttEstimator {
+        RttEstimator {
+            srtt: None,
+            rttvar: Duration::ZERO,
+            base_rto: Duration::from_millis(RDT_TIMEOUT_MS),
+            backoff: 0,
+        }
+    }
+}
+
+impl RttEstimator {
+    pub fn new() -> RttEstimator {
+        Default::default()
+    }
+
+    pub fn rto(&self) -> Duration {
+        let backed_off = self.base_rto.saturating_mul(1 << self.backoff.min(16));
+        backed_off.min(Duration::from_millis(RDT_MAX_RTO_MS))
+    }
+
+    pub fn srtt(&self) -> Option<Duration> {
+        self.srtt
+    }
+
+    pub fn sample(&mut self, rtt: Duration) {
+        let (srtt, rttvar) = match self.srtt {
+            None => (rtt, rtt / 2),
+            Some(srtt) => {
+                let error = rtt.abs_diff(srtt);
+                (srtt * 7 / 8 + rtt / 8, self.rttvar * 3 / 4 + error / 4)
+            }
+        };
+        self.srtt = Some(srtt);
+        self.rttvar = rttvar;
+        let rto = srtt + (rttvar * 4).max(CLOCK_GRANULARITY);
+        self.base_rto = rto.clamp(
+            Duration::from_millis(RDT_MIN_RTO_MS),
+            Duration::from_millis(RDT_MAX_RTO_MS),
+        );
+        self.backoff = 0;
+    }
+
+    // The timer ran out, so wait twice as long next time.
+    pub fn back_off(&mut self) {
+        self.backoff += 1;
+    }
+
+    // The peer answered again, so the timeout goes back to what the samples say. On a lossy
+    // link good samples are rare, and waiting for one would leave every wait at its longest.
+    pub fn restart(&mut self) {
+        self.backoff = 0;
+    }
+}
+
+// The estimator a new session with the peer starts with, without the last one's backing off.
+pub fn estimator_for(rtts: &PeerRtts, peer: SocketAddr) -> RttEstimator {
+    let mut rtt = rtts.lock().unwrap().get(&peer).copied().unwrap_or_default();
+    rtt.restart();
+    rtt
+}
+
+// Keeps what a session learned for the next one with the peer, if it learned anything at all.
+pub fn remember(rtts: &PeerRtts, peer: SocketAddr, rtt: &RttEstimator) {
+    if rtt.srtt().is_some() {
+        rtts.lock().unwrap().insert(peer, *rtt);
+    }
+}
@@ -0,0 +1,185 @@
+// Boots whole nodes inside the test process, each on loopback ports the OS picks and with a
+// temporary shared directory of its own, and drives them with the commands a user would type.
+use p2p::context::{NodeConfig, NodeContext};
+use p2p::dir::index;
+use p2p::networking;
+use p2p::node::Node;
+use p2p::udp::{self, headers::ConnectionType, link::LinkConditions};
+use std::collections::HashSet;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+pub struct TestNode {
+    pub ctx: Arc<NodeContext>,
+    // The node's own list of the peers it knows.
+    pub peers: Arc<RwLock<HashSet<Node>>>,
+    commands: UnboundedSender<String>,
+    dir: TempDir,
+}
+
+// The nodes stop once the cluster is dropped.
+pub struct Cluster {
+    pub nodes: Vec<TestNode>,
+    shutdown: CancellationToken,
+}
+
+impl Cluster {
+    // Every node knows every other one from the start.
+    pub async fn mesh(size: usize, conn_type: ConnectionType) -> Cluster {
+        Cluster::start(size, conn_type, |_| {}, |_, _| true).await
+    }
+
+    // A mesh where everything the nodes send over reliable UDP goes through a bad link.
+    pub async fn lossy_mesh(
+        size: usize,
+        conn_type: ConnectionType,
+        link: LinkConditions,
+    ) -> Cluster {
+        Cluster::start(size, conn_type, |config| config.link = link, |_, _| true).await
+    }
+
+    // A lossy mesh whose nodes fetch every piece from the one data receiver port, like real
+    // nodes do, instead of a fresh one the OS picks each time.
+    pub async fn lossy_mesh_on_fixed_ports(
+        size: usize,
+        conn_type: ConnectionType,
+        link: LinkConditions,
+    ) -> Cluster {
+        let configure = |config: &mut NodeConfig| {
+            config.link = link;
+            config.data_receiver_port = networking::random_data_port();
+        };
+        Cluster::start(size, conn_type, configure, |_, _| true).await
+    }
+
+    // The first node knows all the others and they only know it, the rest is up to discovery.
+    pub async fn star(size: usize, conn_type: ConnectionType) -> Cluster {
+        Cluster::start(size, conn_type, |_| {}, |node, peer| node == 0 || peer == 0).await
+    }
+
+    // Each node only knows its neighbours, so the ends are a few hops apart until discovery
+    // catches up.
+    pub async fn line(size: usize, conn_type: ConnectionType) -> Cluster {
+        Cluster::start(
+            size,
+            conn_type,
+            |_| {},
+            |node, peer| node.abs_diff(peer) == 1,
+        )
+        .await
+    }
+
+    async fn start(
+        size: usize,
+        conn_type: ConnectionType,
+        configure: impl Fn(&mut NodeConfig),
+        knows: impl Fn(usize, usize) -> bool,
+    ) -> Cluster {
+        let shutdown = CancellationToken::new();
+        // Every control socket is bound first, since the starting nodes need their ports.
+        let mut bound = Vec::new();
+        for _ in 0..size {
+            let dir = tempfile::tempdir().unwrap();
+            let mut config = NodeConfig {
+                static_dir: dir.path().to_string_lossy().to_string(),
+                control_port: 0,
+                data_sender_port: 0,
+                data_receiver_port: 0,
+                conn_type,
+                ..Default::default()
+            };
+            configure(&mut config);
+            let ctx = Arc::new(NodeContext::new(config, rand::random()));
+            index::open(&ctx).unwrap();
+            let socket = udp::bind_control_socket(&ctx).await;
+            bound.push((ctx, socket, dir));
+        }
+        let ports: Vec<u16> = bound.iter().map(|(ctx, _, _)| ctx.control_port()).collect();
+        let mut nodes = Vec::new();
+        for (i, (ctx, socket, dir)) in bound.into_iter().enumerate() {
+            let starting: HashSet<Node> = (0..size)
+                .filter(|&j| j != i && knows(i, j))
+                .map(|j| Node::new(&format!("N{}", j), Ipv4Addr::LOCALHOST, ports[j]))
+                .collect();
+            let peers = Arc::new(RwLock::new(starting));
+            let (commands, commands_rx) = mpsc::unbounded_channel();
+            tokio::spawn(udp::run_node(
+                ctx.clone(),
+                socket,
+                peers.clone(),
+                commands_rx,
+                shutdown.clone(),
+            ));
+            nodes.push(TestNode {
+                ctx,
+                peers,
+                commands,
+                dir,
+            });
+        }
+        let cluster = Cluster { nodes, shutdown };
+        let ready = eventually(Duration::from_secs(5), || {
+            cluster.nodes.iter().all(|node| node.ctx.data_port() != 0)
+        })
+        .await;
+        assert!(ready, "the data servers didn't come up");
+        cluster
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        self.shutdown.cancel();
+    }
+}
+
+impl TestNode {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::from((Ipv4Addr::LOCALHOST, self.ctx.control_port()))
+    }
+
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        self.dir.path().join(file_name)
+    }
+
+    // Puts a file in the shared directory and indexes it.
+    pub fn share(&self, file_name: &str, contents: &[u8]) {
+        fs::write(self.path(file_name), contents).unwrap();
+        index::open(&self.ctx).unwrap();
+    }
+
+    pub fn command(&self, line: &str) {
+        self.commands.send(line.to_string()).unwrap();
+    }
+
+    pub fn knows(&self, other: &TestNode) -> bool {
+        let port = other.ctx.control_port();
+        self.peers
+            .read()
+            .unwrap()
+            .iter()
+            .any(|peer| peer.port == port)
+    }
+}
+
+// Whether the condition came true before the time ran out.
+pub async fn eventually(limit: Duration, mut condition: impl FnMut() -> bool) -> bool {
+    let started_at = Instant::now();
+    while started_at.elapsed() < limit {
+        if condition() {
+            return true;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    condition()
+}
+
+pub fn random_bytes(size: usize) -> Vec<u8> {
+    (0..size).map(|_| rand::random()).collect()
+}
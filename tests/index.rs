@@ -0,0 +1,38 @@
+use p2p::context::{NodeConfig, NodeContext};
+use p2p::dir::index;
+use std::fs;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
+
+#[test]
+fn the_watcher_follows_the_directory_until_shutdown() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = NodeConfig {
+        static_dir: dir.path().to_string_lossy().to_string(),
+        ..Default::default()
+    };
+    let ctx = Arc::new(NodeContext::new(config, 1));
+    index::open(&ctx).unwrap();
+    let shutdown = CancellationToken::new();
+    let watcher = {
+        let (ctx, shutdown) = (ctx.clone(), shutdown.clone());
+        thread::spawn(move || index::watch(ctx, shutdown))
+    };
+
+    fs::write(dir.path().join("a.txt"), b"hello there").unwrap();
+    let started_at = Instant::now();
+    while !index::file_names(&ctx).contains(&"a.txt".to_string()) {
+        assert!(
+            started_at.elapsed() < Duration::from_secs(5),
+            "the new file never made it into the index"
+        );
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    shutdown.cancel();
+    let cancelled_at = Instant::now();
+    watcher.join().unwrap();
+    assert!(cancelled_at.elapsed() < Duration::from_secs(2));
+}
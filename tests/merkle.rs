@@ -0,0 +1,18 @@
+use p2p::dir::merkle::{merkle_root, piece_hash};
+
+#[test]
+fn a_piece_cant_pass_for_a_parent() {
+    let (left, right) = (piece_hash(b"left"), piece_hash(b"right"));
+    let root = merkle_root(&[left, right]);
+    let forged = [&[1u8][..], &left, &right].concat();
+    assert_eq!(forged.len(), 65);
+    assert_ne!(piece_hash(&forged), root);
+    assert_ne!(merkle_root(&[piece_hash(&forged)]), root);
+}
+
+#[test]
+fn a_lone_piece_is_its_own_root() {
+    let piece = piece_hash(b"only");
+    assert_eq!(merkle_root(&[piece]), piece);
+    assert_ne!(merkle_root(&[piece, piece]), piece);
+}
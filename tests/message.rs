@@ -0,0 +1,52 @@
+use p2p::networking::BUF_SIZE;
+use p2p::node::Node;
+use p2p::udp::{self, message::EncodeError, message::Message};
+use std::net::Ipv4Addr;
+
+#[test]
+fn discoveries_never_outgrow_a_datagram() {
+    let nodes: Vec<Node> = (0..500u16)
+        .map(|i| Node {
+            name: format!("peer-{}", i),
+            ip: Ipv4Addr::new(10, 0, (i >> 8) as u8, i as u8),
+            port: 8000 + i,
+            id: i as u128 + 1,
+            ..Default::default()
+        })
+        .collect();
+    let messages = udp::discovery_messages(7, &nodes);
+    assert!(messages.len() > 1);
+    let mut told = Vec::new();
+    for message in messages {
+        let datagram = message.encode().unwrap();
+        assert!(datagram.len() <= BUF_SIZE);
+        match Message::decode(&datagram).unwrap() {
+            Message::Discovery { sender_id, nodes } => {
+                assert_eq!(sender_id, 7);
+                told.extend(nodes);
+            }
+            other => panic!("{:?}", other),
+        }
+    }
+    assert_eq!(told, nodes);
+    // Even nobody to tell about says we're alive.
+    assert_eq!(udp::discovery_messages(7, &[]).len(), 1);
+}
+
+#[test]
+fn nothing_is_truncated_to_fit() {
+    let get = |file_name: String| Message::Get {
+        query_id: 1,
+        ttl: 3,
+        file_name,
+    };
+    assert_eq!(
+        get("x".repeat(70_000)).encode(),
+        Err(EncodeError::TooLong(70_000))
+    );
+    assert_eq!(
+        get("x".repeat(9000)).encode(),
+        Err(EncodeError::TooBig(9000 + 21))
+    );
+    assert!(get("x".repeat(100)).encode().is_ok());
+}
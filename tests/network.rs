@@ -0,0 +1,445 @@
+mod harness;
+
+use harness::{eventually, random_bytes, Cluster, TestNode};
+use p2p::dir::digest_of;
+use p2p::networking::{BUF_SIZE, PIECE_SIZE};
+use p2p::node::Node;
+use p2p::udp::headers::{ConnectionType, FrameHeader, PacketHeader};
+use p2p::udp::link::LinkConditions;
+use p2p::udp::message::Message;
+use p2p::udp::query::Route;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+
+// A bit over one piece, so a download takes two data GETs.
+const FILE_SIZE: usize = 70_000;
+
+#[tokio::test(flavor = "multi_thread")]
+async fn discovery_converges() {
+    let cluster = Cluster::star(4, ConnectionType::TCP).await;
+    let converged = eventually(Duration::from_secs(10), || {
+        cluster.nodes.iter().all(|node| {
+            cluster
+                .nodes
+                .iter()
+                .filter(|other| other.addr() != node.addr())
+                .all(|other| node.knows(other))
+        })
+    })
+    .await;
+    assert!(converged, "some nodes never heard of each other");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_is_acked_with_the_file_digest() {
+    let cluster = Cluster::mesh(1, ConnectionType::TCP).await;
+    let node = &cluster.nodes[0];
+    node.share("a.txt", b"hello there");
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut buf = [0; BUF_SIZE];
+
+    let get = Message::Get {
+        query_id: 1,
+        ttl: 1,
+        file_name: String::from("a.txt"),
+    };
+    socket
+        .send_to(&get.encode().unwrap(), node.addr())
+        .await
+        .unwrap();
+    let size = timeout(Duration::from_secs(2), socket.recv(&mut buf))
+        .await
+        .expect("no GET ACK")
+        .unwrap();
+    match Message::decode(&buf[..size]) {
+        Ok(Message::GetAck {
+            query_id,
+            responder,
+            data_port,
+            file_size,
+            digest,
+            file_name,
+            ..
+        }) => {
+            assert_eq!(query_id, 1);
+            assert_eq!(responder, SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+            assert_eq!(data_port, node.ctx.data_port());
+            assert_eq!(file_size, 11);
+            assert_eq!(
+                digest,
+                digest_of(node.path("a.txt").to_str().unwrap()).unwrap()
+            );
+            assert_eq!(file_name, "a.txt");
+        }
+        other => panic!("expected a GET ACK, got {:?}", other),
+    }
+
+    // Files we don't have aren't answered at all.
+    let get = Message::Get {
+        query_id: 2,
+        ttl: 1,
+        file_name: String::from("b.txt"),
+    };
+    socket
+        .send_to(&get.encode().unwrap(), node.addr())
+        .await
+        .unwrap();
+    assert!(timeout(Duration::from_secs(2), socket.recv(&mut buf))
+        .await
+        .is_err());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn offers_of_absurd_sizes_are_dropped() {
+    let cluster = Cluster::mesh(2, ConnectionType::TCP).await;
+    let (seeder, leecher) = (&cluster.nodes[0], &cluster.nodes[1]);
+    // A peer that answers every GET with a file no one could have.
+    let liar = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let liar_port = liar.local_addr().unwrap().port();
+    leecher
+        .peers
+        .write()
+        .unwrap()
+        .insert(Node::new("liar", Ipv4Addr::LOCALHOST, liar_port));
+    tokio::spawn(async move {
+        let mut buf = [0; BUF_SIZE];
+        while let Ok((size, from)) = liar.recv_from(&mut buf).await {
+            if let Ok(Message::Get {
+                query_id,
+                file_name,
+                ..
+            }) = Message::decode(&buf[..size])
+            {
+                let ack = Message::GetAck {
+                    query_id,
+                    responder: SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0),
+                    data_port: liar_port,
+                    file_size: u64::MAX,
+                    digest: [7; 32],
+                    merkle_root: [7; 32],
+                    file_name,
+                };
+                let _ = liar.send_to(&ack.encode().unwrap(), from).await;
+            }
+        }
+    });
+
+    // At first the liar is the only one offering it.
+    leecher.command("get blob.bin");
+    tokio::time::sleep(Duration::from_secs(2)).await;
+
+    let contents = random_bytes(FILE_SIZE);
+    seeder.share("blob.bin", &contents);
+    leecher.command("get blob.bin");
+    let downloaded = leecher.path("blob-1.bin");
+    let finished = eventually(Duration::from_secs(20), || downloaded.exists()).await;
+    assert!(finished, "the download never finished");
+    assert_eq!(fs::read(&downloaded).unwrap(), contents);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_picked_search_result_is_the_content_downloaded() {
+    let cluster = Cluster::mesh(4, ConnectionType::TCP).await;
+    let leecher = &cluster.nodes[3];
+    // Most peers have another file under the same name.
+    let (wanted, common) = (random_bytes(FILE_SIZE), random_bytes(FILE_SIZE));
+    cluster.nodes[0].share("blob.bin", &wanted);
+    cluster.nodes[1].share("blob.bin", &common);
+    cluster.nodes[2].share("blob.bin", &common);
+    let wanted_digest = digest_of(cluster.nodes[0].path("blob.bin").to_str().unwrap()).unwrap();
+
+    leecher.command("search blob");
+    let listed = eventually(Duration::from_secs(5), || {
+        leecher.ctx.last_results.read().unwrap().len() == 2
+    })
+    .await;
+    assert!(listed, "the search never listed both files");
+    let number = leecher
+        .ctx
+        .last_results
+        .read()
+        .unwrap()
+        .iter()
+        .position(|hit| hit.digest == wanted_digest)
+        .unwrap()
+        + 1;
+    leecher.command(&format!("get #{}", number));
+    let downloaded = leecher.path("blob-1.bin");
+    let finished = eventually(Duration::from_secs(20), || downloaded.exists()).await;
+    assert!(finished, "the download never finished");
+    assert_eq!(fs::read(&downloaded).unwrap(), wanted);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_repeated_get_doesnt_start_a_second_download() {
+    let cluster = Cluster::mesh(2, ConnectionType::SAndW).await;
+    let (seeder, leecher) = (&cluster.nodes[0], &cluster.nodes[1]);
+    let contents = random_bytes(FILE_SIZE);
+    seeder.share("blob.bin", &contents);
+    leecher.command("get blob.bin");
+    leecher.command("get blob.bin");
+    // Once more while the pieces are on their way.
+    let part = leecher.path(".blob-1.bin.part");
+    assert!(eventually(Duration::from_secs(5), || part.exists()).await);
+    leecher.command("get blob.bin");
+
+    let downloaded = leecher.path("blob-1.bin");
+    let finished = eventually(Duration::from_secs(60), || downloaded.exists()).await;
+    assert!(finished, "the download never finished");
+    tokio::time::sleep(Duration::from_secs(5)).await;
+    assert_eq!(fs::read(&downloaded).unwrap(), contents);
+    assert!(!leecher.path("blob-2.bin").exists());
+}
+
+// Every piece is a data GET of its own, but all of them are the one download, and a
+// peer's first download isn't held back like a surfer's.
+#[tokio::test(flavor = "multi_thread")]
+async fn a_first_download_of_many_pieces_isnt_delayed() {
+    let cluster = Cluster::mesh(2, ConnectionType::SAndW).await;
+    let (seeder, leecher) = (&cluster.nodes[0], &cluster.nodes[1]);
+    let contents = random_bytes(8 * PIECE_SIZE as usize);
+    seeder.share("blob.bin", &contents);
+    leecher.command("get blob.bin");
+    let downloaded = leecher.path("blob-1.bin");
+    let finished = eventually(Duration::from_secs(10), || downloaded.exists()).await;
+    assert!(finished, "the download never finished in time");
+    assert_eq!(fs::read(&downloaded).unwrap(), contents);
+    let comms = seeder
+        .peers
+        .read()
+        .unwrap()
+        .iter()
+        .find(|peer| peer.port == leecher.ctx.control_port())
+        .map(|peer| peer.prior_communications);
+    assert_eq!(comms, Some(1));
+}
+
+// The GET floods down the line and its ACK is relayed back up, hop by hop.
+#[tokio::test(flavor = "multi_thread")]
+async fn a_get_reaches_the_far_end_of_a_line() {
+    let cluster = Cluster::line(4, ConnectionType::SAndW).await;
+    let (seeder, leecher) = (&cluster.nodes[0], &cluster.nodes[3]);
+    let contents = random_bytes(FILE_SIZE);
+    seeder.share("blob.bin", &contents);
+    assert!(!leecher.knows(seeder));
+    leecher.command("get blob.bin");
+    let downloaded = leecher.path("blob-1.bin");
+    let finished = eventually(Duration::from_secs(60), || downloaded.exists()).await;
+    assert!(finished, "the download never finished");
+    assert_eq!(fs::read(&downloaded).unwrap(), contents);
+    // Both nodes in between remember who to pass the ACK back to.
+    for hop in 1..3 {
+        let upstream = Route::Upstream(cluster.nodes[hop + 1].addr());
+        let routes = cluster.nodes[hop].ctx.routes.lock().unwrap();
+        assert!(routes.values().any(|(route, _)| *route == upstream));
+    }
+}
+
+// Every seeder that ACK'd the GET takes a share of the pieces.
+#[tokio::test(flavor = "multi_thread")]
+async fn a_swarm_of_three_seeders() {
+    let cluster = Cluster::mesh(4, ConnectionType::SAndW).await;
+    let (seeders, leecher) = (&cluster.nodes[..3], &cluster.nodes[3]);
+    let contents = random_bytes(8 * PIECE_SIZE as usize);
+    for seeder in seeders {
+        seeder.share("blob.bin", &contents);
+    }
+    leecher.command("get blob.bin");
+    let downloaded = leecher.path("blob-1.bin");
+    let finished = eventually(Duration::from_secs(60), || downloaded.exists()).await;
+    assert!(finished, "the download never finished");
+    assert_eq!(fs::read(&downloaded).unwrap(), contents);
+    let throughput = leecher.ctx.throughput.lock().unwrap();
+    let sources = seeders
+        .iter()
+        .filter(|seeder| throughput.contains_key(&seeder.addr()))
+        .count();
+    assert!(sources > 1, "only {} seeder sent pieces", sources);
+}
+
+async fn transfer(conn_type: ConnectionType) {
+    transfer_over(conn_type, LinkConditions::default()).await;
+}
+
+// Loses a fifth of what's sent, and mangles a bit more of it on top.
+fn lossy_link() -> LinkConditions {
+    LinkConditions::parse("drop=0.2,dup=0.05,reorder=0.1,corrupt=0.05,delay=5").unwrap()
+}
+
+async fn transfer_over(conn_type: ConnectionType, link: LinkConditions) {
+    let cluster = Cluster::lossy_mesh(2, conn_type, link).await;
+    let (seeder, leecher) = (&cluster.nodes[0], &cluster.nodes[1]);
+    let contents = random_bytes(FILE_SIZE);
+    seeder.share("blob.bin", &contents);
+    leecher.command("get blob.bin");
+    let downloaded = leecher.path("blob-1.bin");
+    let finished = eventually(Duration::from_secs(60), || downloaded.exists()).await;
+    assert!(finished, "the download never finished");
+    assert_eq!(fs::read(&downloaded).unwrap(), contents);
+    // The pieces came from the peer that ACK'd our GET.
+    assert!(leecher
+        .ctx
+        .throughput
+        .lock()
+        .unwrap()
+        .contains_key(&seeder.addr()));
+    // And the RTT the pieces were timed by is kept for the next one.
+    if !matches!(conn_type, ConnectionType::TCP) {
+        let data_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.ctx.data_port()));
+        assert!(leecher.ctx.rtts.lock().unwrap().contains_key(&data_addr));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfer_over_tcp() {
+    transfer(ConnectionType::TCP).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfer_over_stop_and_wait() {
+    transfer(ConnectionType::SAndW).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfer_over_go_back_n() {
+    transfer(ConnectionType::GoBackN).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transfer_over_selective_repeat() {
+    transfer(ConnectionType::SRepeat).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lossy_transfer_over_stop_and_wait() {
+    transfer_over(ConnectionType::SAndW, lossy_link()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lossy_transfer_over_go_back_n() {
+    transfer_over(ConnectionType::GoBackN, lossy_link()).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn lossy_transfer_over_selective_repeat() {
+    transfer_over(ConnectionType::SRepeat, lossy_link()).await;
+}
+
+// A raw socket asking a seeder for ranges of its file, each GET naming a session of its own.
+async fn rdt_session_socket(seeder: &TestNode) -> (UdpSocket, impl Fn(u32, u64) -> Vec<u8>) {
+    let data_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, seeder.ctx.data_port()));
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(data_addr).await.unwrap();
+    let get = |session, offset| {
+        Message::RdtGet {
+            get_port: 0,
+            session,
+            offset,
+            length: Some(10_000),
+            file_name: String::from("blob.bin"),
+        }
+        .encode()
+        .unwrap()
+    };
+    (socket, get)
+}
+
+// Waits for the first data frame of a session, then returns it.
+async fn first_frame_of(socket: &UdpSocket, session: u32, limit: Duration) -> Option<Vec<u8>> {
+    let mut buf = [0; BUF_SIZE];
+    timeout(limit, async {
+        loop {
+            let size = socket.recv(&mut buf).await.unwrap();
+            if let Ok((header, payload)) = FrameHeader::from_bytes(&buf[..size]) {
+                if header.session == session && header.seq == 0 {
+                    return payload.to_vec();
+                }
+            }
+        }
+    })
+    .await
+    .ok()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_get_of_another_session_replaces_the_last_one() {
+    let cluster = Cluster::mesh(1, ConnectionType::GoBackN).await;
+    let seeder = &cluster.nodes[0];
+    let contents = random_bytes(FILE_SIZE);
+    seeder.share("blob.bin", &contents);
+    let (socket, get) = rdt_session_socket(seeder).await;
+
+    // The first session never hears an ACK, so it keeps resending the start of its range.
+    socket.send(&get(1, 0)).await.unwrap();
+    let first = first_frame_of(&socket, 1, Duration::from_secs(2)).await;
+    assert!(first.is_some(), "the first range never came");
+    // The next piece is asked for from the very same port, and so is a retry of the same range.
+    for (session, offset) in [(2, 30_000), (3, 30_000)] {
+        socket.send(&get(session, offset)).await.unwrap();
+        let next = first_frame_of(&socket, session, Duration::from_secs(3)).await;
+        assert!(
+            next.is_some_and(|payload| contents[offset as usize..].starts_with(&payload)),
+            "session {} never came",
+            session
+        );
+    }
+}
+
+// Late ACKs of the last piece reach the sender of the next one from the same address,
+// and must not make it think its packets arrived.
+#[tokio::test(flavor = "multi_thread")]
+async fn late_acks_of_an_earlier_session_are_ignored() {
+    let cluster = Cluster::mesh(1, ConnectionType::SRepeat).await;
+    let seeder = &cluster.nodes[0];
+    seeder.share("blob.bin", &random_bytes(FILE_SIZE));
+    let (socket, get) = rdt_session_socket(seeder).await;
+
+    socket.send(&get(1, 0)).await.unwrap();
+    assert!(first_frame_of(&socket, 1, Duration::from_secs(2))
+        .await
+        .is_some());
+    socket.send(&get(2, 0)).await.unwrap();
+    assert!(first_frame_of(&socket, 2, Duration::from_secs(2))
+        .await
+        .is_some());
+    for seq in 0..8 {
+        let late_ack = FrameHeader::new(PacketHeader::SRepeat, 1, seq).as_vec();
+        socket.send(&late_ack).await.unwrap();
+    }
+    // Nothing was ACK'd in session 2, so its first packet times out and comes again.
+    let resent = first_frame_of(&socket, 2, Duration::from_secs(5)).await;
+    assert!(
+        resent.is_some(),
+        "a late ACK was taken for one of the new session"
+    );
+}
+
+// Every piece is fetched from the same port, so a new piece's GET reaches the sender from the
+// address the last piece's session, maybe still resending its FIN, was started from.
+#[tokio::test(flavor = "multi_thread")]
+async fn lossy_pieces_from_a_fixed_port() {
+    let cluster =
+        Cluster::lossy_mesh_on_fixed_ports(2, ConnectionType::GoBackN, lossy_link()).await;
+    let (seeder, leecher) = (&cluster.nodes[0], &cluster.nodes[1]);
+    let contents = random_bytes(3 * PIECE_SIZE as usize);
+    seeder.share("blob.bin", &contents);
+    leecher.command("get blob.bin");
+    let downloaded = leecher.path("blob-1.bin");
+    let finished = eventually(Duration::from_secs(90), || downloaded.exists()).await;
+    assert!(finished, "the download never finished");
+    assert_eq!(fs::read(&downloaded).unwrap(), contents);
+}
+
+#[test]
+fn link_conditions_parse() {
+    let link = LinkConditions::parse("drop=0.2,delay=20").unwrap();
+    assert_eq!(link.drop_rate, 0.2);
+    assert_eq!(link.delay, Duration::from_millis(20));
+    assert_eq!(link.corrupt_rate, 0.0);
+    assert!(LinkConditions::parse("").unwrap().is_perfect());
+    assert!(LinkConditions::parse("drop=1.5").is_err());
+    assert!(LinkConditions::parse("jitter=3").is_err());
+}
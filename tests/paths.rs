@@ -0,0 +1,75 @@
+use p2p::context::NodeConfig;
+use p2p::dir::{normalize_shared_path, resolve_shared};
+use std::fs;
+
+#[test]
+fn parent_components_are_refused() {
+    assert_eq!(normalize_shared_path(".."), None);
+    assert_eq!(normalize_shared_path("../secret"), None);
+    assert_eq!(normalize_shared_path("music/../../secret"), None);
+    assert_eq!(normalize_shared_path("music/.."), None);
+}
+
+#[test]
+fn absolute_paths_are_refused() {
+    assert_eq!(normalize_shared_path("/etc/passwd"), None);
+    assert_eq!(normalize_shared_path("//server/share"), None);
+}
+
+#[test]
+fn backslashes_and_drive_prefixes_are_refused() {
+    assert_eq!(normalize_shared_path("..\\secret"), None);
+    assert_eq!(normalize_shared_path("music\\song.mp3"), None);
+    assert_eq!(normalize_shared_path("C:\\Windows\\win.ini"), None);
+    assert_eq!(normalize_shared_path("C:secret"), None);
+    assert_eq!(normalize_shared_path("\\\\server\\share"), None);
+}
+
+#[test]
+fn dot_components_are_dropped_and_hidden_ones_refused() {
+    assert_eq!(
+        normalize_shared_path("./song.mp3"),
+        Some("song.mp3".to_string())
+    );
+    assert_eq!(
+        normalize_shared_path("music/./rock//song.mp3"),
+        Some("music/rock/song.mp3".to_string())
+    );
+    assert_eq!(normalize_shared_path("."), None);
+    assert_eq!(normalize_shared_path(""), None);
+    assert_eq!(normalize_shared_path(".netwolf-index"), None);
+    assert_eq!(normalize_shared_path("music/.hidden/song.mp3"), None);
+}
+
+#[cfg(unix)]
+#[test]
+fn symlinks_only_resolve_inside_the_shared_directory() {
+    use std::os::unix::fs::symlink;
+
+    let root = tempfile::tempdir().unwrap();
+    let shared = root.path().join("shared");
+    let outside = root.path().join("outside");
+    fs::create_dir_all(shared.join("music")).unwrap();
+    fs::create_dir_all(&outside).unwrap();
+    fs::write(shared.join("music/song.mp3"), b"song").unwrap();
+    fs::write(outside.join("secret"), b"secret").unwrap();
+    symlink(outside.join("secret"), shared.join("leak")).unwrap();
+    symlink(&outside, shared.join("elsewhere")).unwrap();
+    symlink(shared.join("music/song.mp3"), shared.join("alias.mp3")).unwrap();
+    let config = NodeConfig {
+        static_dir: shared.to_string_lossy().to_string(),
+        ..Default::default()
+    };
+
+    assert_eq!(resolve_shared(&config, "leak"), None);
+    assert_eq!(resolve_shared(&config, "elsewhere/secret"), None);
+    assert_eq!(resolve_shared(&config, "../outside/secret"), None);
+    assert_eq!(
+        resolve_shared(&config, "alias.mp3"),
+        Some(fs::canonicalize(shared.join("music/song.mp3")).unwrap())
+    );
+    assert_eq!(
+        resolve_shared(&config, "./music/song.mp3"),
+        Some(fs::canonicalize(shared.join("music/song.mp3")).unwrap())
+    );
+}
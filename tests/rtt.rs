@@ -0,0 +1,88 @@
+use p2p::networking::{RDT_MAX_RTO_MS, RDT_MIN_RTO_MS, RDT_TIMEOUT_MS};
+use p2p::udp::rtt::{self, PeerRtts, RttEstimator};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+fn ms(ms: u64) -> Duration {
+    Duration::from_millis(ms)
+}
+
+#[test]
+fn starts_from_the_fixed_timeout() {
+    let rtt = RttEstimator::new();
+    assert_eq!(rtt.srtt(), None);
+    assert_eq!(rtt.rto(), ms(RDT_TIMEOUT_MS));
+}
+
+#[test]
+fn follows_the_samples() {
+    let mut rtt = RttEstimator::new();
+    // The first sample sets the variance to half of it.
+    rtt.sample(ms(100));
+    assert_eq!(rtt.srtt(), Some(ms(100)));
+    assert_eq!(rtt.rto(), ms(300));
+    rtt.sample(ms(200));
+    assert_eq!(rtt.srtt(), Some(ms(112) + Duration::from_micros(500)));
+    assert_eq!(rtt.rto(), ms(362) + Duration::from_micros(500));
+    // A steady link settles on a timeout close to its RTT.
+    for _ in 0..100 {
+        rtt.sample(ms(200));
+    }
+    assert!(rtt.rto() < ms(210), "{:?}", rtt.rto());
+}
+
+#[test]
+fn stays_within_bounds() {
+    let mut rtt = RttEstimator::new();
+    rtt.sample(Duration::from_micros(50));
+    assert_eq!(rtt.rto(), ms(RDT_MIN_RTO_MS));
+    rtt.sample(ms(60_000));
+    assert_eq!(rtt.rto(), ms(RDT_MAX_RTO_MS));
+}
+
+#[test]
+fn backs_off_until_the_next_sample() {
+    let mut rtt = RttEstimator::new();
+    rtt.sample(ms(100));
+    rtt.back_off();
+    assert_eq!(rtt.rto(), ms(600));
+    rtt.back_off();
+    assert_eq!(rtt.rto(), ms(1200));
+    for _ in 0..40 {
+        rtt.back_off();
+    }
+    assert_eq!(rtt.rto(), ms(RDT_MAX_RTO_MS));
+    rtt.sample(ms(100));
+    assert!(rtt.rto() < ms(600));
+}
+
+#[test]
+fn hearing_back_undoes_the_backoff() {
+    let mut rtt = RttEstimator::new();
+    rtt.back_off();
+    rtt.back_off();
+    assert_eq!(rtt.rto(), ms(RDT_MAX_RTO_MS.min(4 * RDT_TIMEOUT_MS)));
+    // Without a sample there's nothing better than where it started.
+    rtt.restart();
+    assert_eq!(rtt.rto(), ms(RDT_TIMEOUT_MS));
+}
+
+#[test]
+fn the_next_session_with_a_peer_starts_from_what_the_last_one_learned() {
+    let rtts = PeerRtts::default();
+    let (peer, other): (SocketAddr, SocketAddr) = (
+        "127.0.0.1:4000".parse().unwrap(),
+        "127.0.0.1:4001".parse().unwrap(),
+    );
+    let mut rtt = rtt::estimator_for(&rtts, peer);
+    assert_eq!(rtt.rto(), ms(RDT_TIMEOUT_MS));
+    rtt.sample(ms(100));
+    rtt.back_off();
+    rtt::remember(&rtts, peer, &rtt);
+    // It's the timeout the samples call for, the backing off was that session's own.
+    assert_eq!(rtt::estimator_for(&rtts, peer).rto(), ms(300));
+    assert_eq!(rtt::estimator_for(&rtts, other).rto(), ms(RDT_TIMEOUT_MS));
+    // A session without a single sample has nothing to teach the next one.
+    rtt::remember(&rtts, other, &RttEstimator::new());
+    assert!(rtts.lock().unwrap().get(&other).is_none());
+}
@@ -0,0 +1,49 @@
+use p2p::search;
+use std::time::{Duration, Instant};
+
+#[test]
+fn globs_and_substrings_ignore_case() {
+    assert!(search::matches("*.MP3", "music/song.mp3"));
+    assert!(search::matches("song?.mp3", "song1.mp3"));
+    assert!(search::matches("s*g*3", "song.mp3"));
+    assert!(search::matches("*", ""));
+    assert!(search::matches("Song", "music/song.mp3"));
+    assert!(!search::matches("*.mp3", "song.mp4"));
+    assert!(!search::matches("song?.mp3", "song.mp3"));
+    assert!(!search::matches("a*b", "ab-"));
+}
+
+#[test]
+fn many_stars_dont_blow_up() {
+    let started = Instant::now();
+    let name = "a".repeat(40);
+    assert!(!search::matches("*a*a*a*a*a*a*a*a*b", &name));
+    let pattern = format!("{}b", "*a".repeat(200));
+    assert!(!search::matches(&pattern, &"a".repeat(4000)));
+    assert!(started.elapsed() < Duration::from_secs(1));
+}
+
+#[test]
+fn oversized_patterns_are_refused() {
+    assert!(search::valid_pattern("*.mp3"));
+    assert!(!search::valid_pattern(&"x".repeat(10_000)));
+    assert!(!search::valid_pattern(&"*a".repeat(100)));
+}
+
+#[test]
+fn answers_never_outgrow_a_datagram() {
+    let hit = |file_name: String| search::SearchHit {
+        file_name,
+        size: 1,
+        digest: [0; 32],
+    };
+    let huge = "x".repeat(9000);
+    assert!(search::result_messages(1, &huge, vec![hit("a".to_string())]).is_empty());
+
+    let hits = (0..500).map(|i| hit(format!("file-{}.bin", i))).collect();
+    let messages = search::result_messages(1, "file", hits);
+    assert!(messages.len() > 1);
+    for message in messages {
+        assert!(message.encode().unwrap().len() <= p2p::networking::BUF_SIZE);
+    }
+}
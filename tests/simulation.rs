@@ -0,0 +1,93 @@
+use p2p::sim::{self, Simulation};
+use p2p::udp::link::LinkConditions;
+
+fn lossy_link() -> LinkConditions {
+    LinkConditions::parse("drop=0.2,dup=0.05,reorder=0.1,corrupt=0.05,delay=5").unwrap()
+}
+
+// Each node only knows its neighbours to begin with.
+fn line(seed: u64, size: usize, link: LinkConditions) -> Simulation {
+    let mut simulation = Simulation::new(seed, link);
+    for _ in 0..size {
+        simulation.add_node();
+    }
+    for node in 1..size {
+        simulation.connect(node - 1, node);
+    }
+    simulation
+}
+
+#[test]
+fn a_seed_replays_the_same_run() {
+    let first = sim::demo(42, lossy_link());
+    let second = sim::demo(42, lossy_link());
+    assert!(!first.trace().is_empty());
+    assert_eq!(first.trace(), second.trace());
+
+    let other = sim::demo(43, lossy_link());
+    assert_ne!(first.trace(), other.trace());
+}
+
+#[test]
+fn discovery_converges_in_simulation() {
+    let mut simulation = line(1, 10, LinkConditions::default());
+    simulation.run_until(30_000);
+    let nodes = simulation.nodes();
+    for (i, node) in nodes.iter().enumerate() {
+        for (j, other) in nodes.iter().enumerate() {
+            assert!(i == j || node.knows(other), "{} never heard of {}", i, j);
+        }
+    }
+}
+
+#[test]
+fn flooded_get_is_answered_from_hops_away() {
+    let mut simulation = line(7, 5, LinkConditions::default());
+    let contents = simulation.random_bytes(100_000);
+    simulation.share(0, "blob.bin", &contents);
+    // Right away, before discovery shortens the path, so the GET has to travel every hop.
+    simulation.command(4, 0, "get blob.bin");
+    simulation.run_until(500);
+    let seeder = simulation.node(0).control_addr();
+    assert_eq!(simulation.node(4).offers("blob.bin"), &[seeder]);
+}
+
+#[test]
+fn queries_dont_travel_past_their_ttl() {
+    let mut simulation = line(7, 6, LinkConditions::default());
+    let contents = simulation.random_bytes(1_000);
+    simulation.share(0, "blob.bin", &contents);
+    simulation.command(5, 0, "get blob.bin");
+    simulation.command(5, 0, "search blob");
+    simulation.run_until(500);
+    assert!(simulation.node(5).offers("blob.bin").is_empty());
+    assert!(simulation.node(5).found("blob").is_empty());
+}
+
+// Control messages have no checksum of their own, UDP's takes care of that on a real link.
+fn lossy_uncorrupted_link() -> LinkConditions {
+    LinkConditions::parse("drop=0.2,dup=0.05,reorder=0.1,delay=5").unwrap()
+}
+
+#[test]
+fn flooded_search_finds_files_over_a_lossy_link() {
+    for seed in 0..5 {
+        let mut simulation = line(seed, 5, lossy_uncorrupted_link());
+        for file_name in ["a.txt", "b.txt", "c.bin"] {
+            let contents = simulation.random_bytes(1_000);
+            simulation.share(0, file_name, &contents);
+        }
+        // Asked over and over, as a user would when nothing comes back.
+        for attempt in 0..20 {
+            simulation.command(4, 5_000 + attempt * 1_000, "search *.txt");
+        }
+        simulation.run_until(30_000);
+        let found: Vec<&str> = simulation
+            .node(4)
+            .found("*.txt")
+            .iter()
+            .map(|(_, hit)| &hit.file_name[..])
+            .collect();
+        assert_eq!(found, ["a.txt", "b.txt"], "seed {}, see its trace", seed);
+    }
+}